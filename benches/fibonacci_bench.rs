@@ -1,23 +1,49 @@
 #[macro_use]
 extern crate criterion;
 
-//use criterion::black_box;
+use criterion::black_box;
 use criterion::Criterion;
 
-fn fibonacci() {
-    //    use crate::*;
-    use std::fs;
-    let path = "./demos/fibonacci.est";
-    let buffer = fs::read_to_string(path).expect("Couldn't read file!");
-    match esta::run(&buffer) {
-        Ok(()) => {}
-        Err(why) => eprintln!("{}", why),
+const PATH: &str = "./examples/fibonacci.est";
+
+fn fibonacci(buffer: &str) {
+    match esta::run(buffer) {
+        Ok(result) => {
+            black_box(result);
+        }
+        Err(errors) => {
+            for why in errors {
+                eprintln!("{}", why);
+            }
+        }
     }
 }
 
+// How much the peephole pass shrinks the benchmarked program
+fn peephole_counts() {
+    use esta::{backend, frontend, middleend};
+    use std::fs;
+    let buffer = fs::read_to_string(PATH).expect("Couldn't read file!");
+    let stmts = frontend::run(&buffer).expect("Couldn't parse program");
+    let (stmts, md) = middleend::run(stmts).expect("Couldn't check program");
+    let program = backend::generate(stmts, md).expect("Couldn't compile program");
+    println!(
+        "Peephole: {} instructions before, {} after",
+        program.peephole.before, program.peephole.after
+    );
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("Fib 30", |b| b.iter(|| fibonacci()));
+    use std::fs;
+    peephole_counts();
+    let buffer = fs::read_to_string(PATH).expect("Couldn't read file!");
+    c.bench_function("Fib 30", move |b| b.iter(|| fibonacci(&buffer)));
 }
 
-criterion_group!(benches, criterion_benchmark);
+// Every iteration runs the whole program, so fewer samples are taken
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = criterion_benchmark
+}
 criterion_main!(benches);
@@ -1,30 +1,252 @@
+pub mod peephole;
 pub mod program;
+#[cfg(test)]
+mod tests;
 
+use self::peephole::{Counts, Peephole};
 use self::program::{AsmCtx, Program};
+use crate::error::{EstaError, Span};
 use crate::frontend::ast::*;
+use crate::middleend::resolver::Slot;
+use crate::middleend::typecheck::Type;
 use crate::middleend::MetaData;
 use crate::util::fold::*;
-use crate::util::{bool_to_i64, string_hash32};
 use crate::vm::bytecode::*;
-use crate::vm::{EstaData, EstaType};
-use itertools::Itertools;
-use std::collections::HashMap;
+use crate::vm::{EstaData, EstaKind};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-pub fn generate(stmts: Stmt, md: MetaData) -> Result<Program, &'static str> {
-    Assembler::assemble(&stmts)
+lazy_static! {
+    static ref BIN_OP_TO_BYTE: HashMap<Opcode, ByteCode> = {
+        let mut m = HashMap::new();
+        m.insert(Opcode::Add, ByteCode::ADD);
+        m.insert(Opcode::Sub, ByteCode::SUB);
+        m.insert(Opcode::Mul, ByteCode::MUL);
+        m.insert(Opcode::Div, ByteCode::DIV);
+        m.insert(Opcode::Mod, ByteCode::MOD);
+        m.insert(Opcode::Greater, ByteCode::GT);
+        m.insert(Opcode::GreaterEqual, ByteCode::GE);
+        m.insert(Opcode::Lesser, ByteCode::LT);
+        m.insert(Opcode::LesserEqual, ByteCode::LE);
+        m.insert(Opcode::EqualEqual, ByteCode::EQ);
+        m.insert(Opcode::BangEqual, ByteCode::NE);
+        m
+    };
+    static ref UN_OP_TO_BYTE: HashMap<Opcode, ByteCode> = {
+        let mut m = HashMap::new();
+        m.insert(Opcode::Not, ByteCode::NOT);
+        m.insert(Opcode::Sub, ByteCode::NEG);
+        m
+    };
+}
+
+pub fn generate(stmts: Stmt, md: MetaData) -> Result<Program, EstaError> {
+    Assembler::assemble(&stmts, md)
 }
 
 pub struct Assembler;
 
 impl Assembler {
-    pub fn assemble(body: &Stmt) -> Result<Program, &'static str> {
-        let ctx: AsmCtx = Default::default();
-        let ctx = Assembler::fold_stmt(&ctx, body).ok_or("Failed to assemble program")?;
+    pub fn assemble(body: &Stmt, md: MetaData) -> Result<Program, EstaError> {
+        let tags: HashMap<&String, usize> = md.structs.iter().map(|s| (&s.id, s.tag)).collect();
+        let guards = md.guards.iter().filter_map(|(span, ty)| {
+            let guard = match ty {
+                Type::Dynamic => return None,
+                Type::Num => (EstaKind::Num, 0),
+                Type::Bool => (EstaKind::Bool, 0),
+                Type::Str => (EstaKind::Str, 0),
+                Type::List => (EstaKind::List, 0),
+                Type::Nil => (EstaKind::Nil, 0),
+                Type::Struct(id) => (EstaKind::Struct, *tags.get(id)?),
+            };
+            Some((*span, guard))
+        });
+        let ctx = AsmCtx {
+            slots: Rc::new(md.slots),
+            guards: Rc::new(guards.collect()),
+            ..Default::default()
+        };
+        let mut ctx = Assembler::fold_stmt(&ctx, body)
+            .ok_or_else(|| EstaError::compile("Failed to assemble program"))?;
+        for s in md.structs.iter() {
+            ctx.functions.push(Assembler::make_constructor(s));
+        }
+        let ctx = Assembler::link_natives(ctx);
+        let (ctx, globals) = Assembler::bootstrap_startup(ctx);
+        let (ctx, counts) = Assembler::peephole(ctx);
+
+        let mut program = ctx.assemble()?;
+        program.structs = md.structs;
+        program.globals = globals;
+        program.peephole = counts;
+        Ok(program)
+    }
+
+    /// Each section of code is optimized on its own, as nothing falls through from
+    /// one section into the next
+    fn peephole(mut ctx: AsmCtx) -> (AsmCtx, Counts) {
+        let count = |ctx: &AsmCtx| {
+            ctx.functions
+                .iter()
+                .map(|(_, _, body)| Peephole::count(body))
+                .sum::<usize>()
+                + Peephole::count(&ctx.blocks)
+        };
+        let before = count(&ctx);
+        ctx.blocks = Peephole::optimize(ctx.blocks);
+        for (_, _, body) in ctx.functions.iter_mut() {
+            *body = Peephole::optimize(std::mem::take(body));
+        }
+        let after = count(&ctx);
+        (ctx, Counts { before, after })
+    }
+
+    /// Every struct gets a constructor function of the same name, which allocates
+    /// a new instance with all fields set to Nil and returns its address.
+    fn make_constructor(s: &EstaStruct) -> (String, usize, Vec<MetaInst>) {
+        let blocks = vec![
+            MetaInst::ByteCode(ByteCode::NEW),
+            MetaInst::Operand(s.tag),
+            MetaInst::Operand(s.size),
+            MetaInst::ByteCode(ByteCode::RET),
+        ];
+        (s.id.clone(), 0, blocks)
+    }
+
+    /// Calls are compiled before every function has been seen, so all of them start
+    /// out as a CALL to the function's label. Once everything is compiled, any call
+    /// to a name that isn't a function or a struct constructor becomes a CALLN to
+    /// the native function of that name.
+    fn link_natives(mut ctx: AsmCtx) -> AsmCtx {
+        let names: HashSet<String> = ctx.functions.iter().map(|(n, _, _)| n.clone()).collect();
+        let link = |blocks: &mut Vec<MetaInst>| {
+            for idx in 1..blocks.len() {
+                if let (MetaInst::ByteCode(ByteCode::CALL), MetaInst::Label(id)) =
+                    (&blocks[idx - 1], &blocks[idx])
+                {
+                    if !names.contains(id) {
+                        blocks[idx] = MetaInst::Native(id.clone());
+                        blocks[idx - 1] = MetaInst::ByteCode(ByteCode::CALLN);
+                    }
+                }
+            }
+        };
+        link(&mut ctx.blocks);
+        for (_, _, body) in ctx.functions.iter_mut() {
+            link(body);
+        }
+        ctx
+    }
+
+    /// Logical operators are lowered like an if/else expression on the left operand:
+    /// `lhs and rhs` is rhs when lhs is True and False otherwise, `lhs or rhs` is True
+    /// when lhs is True and rhs otherwise. Either way, rhs is only evaluated when needed.
+    fn fold_logical(down: &AsmCtx, lhs: &Expr, op: &Opcode, rhs: &Expr) -> Option<AsmCtx> {
+        let mut down = down.fork();
+        let alter_lbl = down.next_label();
+        let cont_lbl = down.next_label();
+
+        let lhs = Self::fold_expr(&down, lhs)?;
+        let rhs = Self::fold_expr(&lhs.fork(), rhs)?;
+        let mut ctx = rhs.fork();
+
+        let short_circuit = |value| {
+            vec![
+                MetaInst::ByteCode(ByteCode::LOADC),
+                MetaInst::Const(EstaData::new_bool(value)),
+            ]
+        };
+        let (body, alter) = match op {
+            Opcode::And => (rhs.blocks, short_circuit(false)),
+            _ => (short_circuit(true), rhs.blocks),
+        };
+
+        ctx.blocks.extend(lhs.blocks);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::JUMPF));
+        ctx.blocks.push(MetaInst::Label(alter_lbl.clone()));
+        ctx.blocks.extend(body);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::JUMP));
+        ctx.blocks.push(MetaInst::Label(cont_lbl.clone()));
+        ctx.blocks.push(MetaInst::Label(alter_lbl));
+        ctx.blocks.extend(alter);
+        ctx.blocks.push(MetaInst::Label(cont_lbl));
+
+        ctx.functions = [lhs.functions, rhs.functions].concat();
+        Some(ctx)
+    }
+
+    /// The startup prelude runs any top level statements and then halts. The VM
+    /// sets up the global frame before it starts and calls main once it halts.
+    /// The global variables are the declarations left over at the top level.
+    fn bootstrap_startup(mut ctx: AsmCtx) -> (AsmCtx, Vec<String>) {
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::HALT));
+        let globals = std::mem::take(&mut ctx.declarations);
+        (ctx, globals)
+    }
+
+    /// Loads or stores a variable at the slot the resolver found for it. Locals are
+    /// addressed by how many frames up they are, globals by their index alone.
+    fn variable(ctx: &mut AsmCtx, id: &Identifier, store: bool) -> Option<()> {
+        match ctx.slots.get(&id.span)? {
+            Slot::Local { depth, index } => {
+                let op = if store {
+                    ByteCode::STOREV
+                } else {
+                    ByteCode::LOADV
+                };
+                ctx.blocks.push(MetaInst::ByteCode(op));
+                ctx.blocks.push(MetaInst::Operand(*depth));
+                ctx.blocks.push(MetaInst::Operand(*index));
+            }
+            Slot::Global(index) => {
+                let op = if store {
+                    ByteCode::STOREG
+                } else {
+                    ByteCode::LOADG
+                };
+                ctx.blocks.push(MetaInst::ByteCode(op));
+                ctx.blocks.push(MetaInst::Operand(*index));
+            }
+        }
+        Some(())
+    }
+
+    /// Values flowing into something annotated with a type they might not have
+    /// are guarded by a CHECK, which leaves the value on the stack
+    fn guard(ctx: &mut AsmCtx, span: Span) {
+        if let Some((kind, tag)) = ctx.guards.get(&span) {
+            ctx.blocks.push(MetaInst::Span(span));
+            ctx.blocks.push(MetaInst::ByteCode(ByteCode::CHECK));
+            ctx.blocks.push(MetaInst::Operand(*kind as usize));
+            ctx.blocks.push(MetaInst::Operand(*tag));
+        }
+    }
 
-        let instructions = ctx.assemble();
-        Ok(instructions)
+    /// Returns Nil from a function, guarded at span if its return is annotated
+    fn return_nil(ctx: &mut AsmCtx, span: Span) {
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::LOADC));
+        ctx.blocks.push(MetaInst::Const(Default::default()));
+        Assembler::guard(ctx, span);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::RET));
+    }
 
-        //        Err("Not implemented")
+    /// Folds each child in order, handing every sibling a context that continues
+    /// the label numbering of the one before it so that no two labels collide.
+    fn fold_seq<T, F>(down: &AsmCtx, children: &[T], f: F) -> Vec<Option<AsmCtx>>
+    where
+        F: Fn(&AsmCtx, &T) -> Option<AsmCtx>,
+    {
+        let mut down = down.fork();
+        children
+            .iter()
+            .map(|c| {
+                let child = f(&down, c);
+                if let Some(child) = &child {
+                    down = child.fork();
+                }
+                child
+            })
+            .collect()
     }
 }
 
@@ -37,22 +259,26 @@ impl Fold for Assembler {
     fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
         let children = children.into_iter().flatten().collect::<Vec<Self::UpT>>();
 
-        if children.len() > 0 {
+        if !children.is_empty() {
             let last_ctx = children.last().cloned().unwrap();
             let declarations = children
                 .iter()
                 .cloned()
-                .map(|c: AsmCtx| -> Vec<String> { c.declarations })
-                .flatten()
+                .flat_map(|c: AsmCtx| -> Vec<String> { c.declarations })
+                .collect();
+            let functions = children
+                .iter()
+                .cloned()
+                .flat_map(|c: AsmCtx| -> Vec<(String, usize, Vec<MetaInst>)> { c.functions })
                 .collect();
             let blocks = children
                 .into_iter()
-                .map(|c: AsmCtx| -> Vec<MetaInst> { c.blocks })
-                .flatten()
+                .flat_map(|c: AsmCtx| -> Vec<MetaInst> { c.blocks })
                 .collect();
             Some(AsmCtx {
                 blocks,
                 declarations,
+                functions,
                 ..last_ctx
             })
         } else {
@@ -60,447 +286,309 @@ impl Fold for Assembler {
         }
     }
 
-    fn fold_block(down: &Self::DownT, body: &Vec<Box<Stmt>>, is_scope: &bool) -> Option<Self::UpT> {
-        let children = body.iter().map(|b| Self::fold_stmt(down, b)).collect();
+    /// A scoped block allocates a new environment frame for the variables declared
+    /// directly inside of it. An unscoped block hands its declarations up to the
+    /// nearest enclosing scope.
+    fn fold_block(down: &Self::DownT, body: &[Box<Stmt>], is_scope: &bool) -> Option<Self::UpT> {
+        let children = Assembler::fold_seq(down, body, |d, b| Self::fold_stmt(d, b));
+        let mut child = Assembler::reduce(children).unwrap_or_else(|| down.fork());
 
-        if let Some(mut child) = Assembler::reduce(children) {
+        if *is_scope {
             let mut block = Vec::new();
             block.push(MetaInst::ByteCode(ByteCode::PUSHE));
-            block.push(MetaInst::Number(child.declarations.len() as i16));
-            for id in child.declarations {
-                block.push(MetaInst::Declaration(id.clone()));
-            }
+            block.push(MetaInst::Operand(child.declarations.len()));
             child.declarations = Vec::new();
 
             block.extend(child.blocks);
             block.push(MetaInst::ByteCode(ByteCode::POPE));
             child.blocks = block;
-            return Some(child);
-        } else {
-            return Some(Default::default());
         }
+        Some(child)
+    }
+
+    // The test is evaluated first, if it is false, jump over the body to the
+    // alternate. Otherwise fall through into the body and then jump over the alternate.
+    fn fold_if(down: &Self::DownT, test: &Expr, body: &Stmt, alter: &Stmt) -> Option<Self::UpT> {
+        let mut down = down.fork();
+        let alter_lbl = down.next_label();
+        let cont_lbl = down.next_label();
+
+        let test = Self::fold_expr(&down, test).unwrap_or_else(|| down.fork());
+        let body = Self::fold_stmt(&test.fork(), body).unwrap_or_else(|| test.fork());
+        let alter = Self::fold_stmt(&body.fork(), alter).unwrap_or_else(|| body.fork());
+        let mut ctx = alter.fork();
+
+        // Test Block
+        ctx.blocks.extend(test.blocks);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::JUMPF));
+        ctx.blocks.push(MetaInst::Label(alter_lbl.clone()));
+
+        // Body Block
+        ctx.blocks.extend(body.blocks);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::JUMP));
+        ctx.blocks.push(MetaInst::Label(cont_lbl.clone()));
+
+        // Alternate Block
+        ctx.blocks.push(MetaInst::Label(alter_lbl));
+        ctx.blocks.extend(alter.blocks);
+
+        // Continuation Block
+        ctx.blocks.push(MetaInst::Label(cont_lbl));
+
+        ctx.functions = [test.functions, body.functions, alter.functions].concat();
+        Some(ctx)
     }
 
-    //    fn fold_if(
-    //        down: &Self::DownT,
-    //        test: &Box<Expr>,
-    //        body: &Box<Stmt>,
-    //        alter: &Box<Stmt>,
-    //    ) -> Option<Self::UpT> {
-    //        let mut down = down.clone();
-    //        let alter_lbl = down.next_label();
-    //        let cont_lbl = down.next_label();
-    //
-    //        let test = Self::fold_expr(&down, test).unwrap_or_default();
-    //        let body = Self::fold_stmt(&down, body).unwrap_or_default();
-    //        let alter = Self::fold_stmt(&down, alter).unwrap_or_default();
-    //
-    //        let base = alter.base.clone();
-    //        let suffix = alter.suffix.clone();
-    //
-    //        // Test Block
-    //        let mut blocks = Vec::new();
-    //        blocks.extend(test.blocks);
-    //        // TODO: It is inelegant that we need to use labels as jump arguments and as
-    //        //  undetermined points to jump to
-    //        blocks.push(MetaInst::ByteCode(ByteCode::JUMPF));
-    //        blocks.push(MetaInst::Label(alter_lbl.clone()));
-    //
-    //        // Body Block
-    //        blocks.extend(body.blocks);
-    //        blocks.push(MetaInst::ByteCode(ByteCode::JUMP));
-    //        blocks.push(MetaInst::Label(cont_lbl.clone()));
-    //
-    //        // Alternate Block
-    //        blocks.push(MetaInst::Label(alter_lbl.clone()));
-    //        blocks.extend(alter.blocks);
-    //
-    //        // Continuation Block
-    //        blocks.push(MetaInst::Label(cont_lbl.clone()));
-    //
-    //        // TODO: Replace with ..alter constructor
-    //        Some(AsmCtx {
-    //            base,
-    //            blocks,
-    //            suffix,
-    //        })
-    //    }
+    // The test is evaluated at the head of every iteration. Once it is false, jump
+    // past the body, otherwise run the body and jump back to the head.
+    fn fold_while(down: &Self::DownT, test: &Expr, body: &Stmt) -> Option<Self::UpT> {
+        let mut down = down.fork();
+        let test_lbl = down.next_label();
+        let cont_lbl = down.next_label();
+
+        let test = Self::fold_expr(&down, test).unwrap_or_else(|| down.fork());
+        let body = Self::fold_stmt(&test.fork(), body).unwrap_or_else(|| test.fork());
+        let mut ctx = body.fork();
+
+        // Test Block
+        ctx.blocks.push(MetaInst::Label(test_lbl.clone()));
+        ctx.blocks.extend(test.blocks);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::JUMPF));
+        ctx.blocks.push(MetaInst::Label(cont_lbl.clone()));
+
+        // Body Block
+        ctx.blocks.extend(body.blocks);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::JUMP));
+        ctx.blocks.push(MetaInst::Label(test_lbl));
+
+        // Continuation Block
+        ctx.blocks.push(MetaInst::Label(cont_lbl));
+
+        ctx.functions = [test.functions, body.functions].concat();
+        Some(ctx)
+    }
+
+    // Returning evaluates the value (or Nil) and hands it back to the caller. The VM
+    // unwinds every environment and stack frame the function pushed on the way out.
+    // A bare return is assembled by fold_stmt, which knows the span to guard it at
+    fn fold_return(down: &Self::DownT, value: &Option<Box<Expr>>) -> Option<Self::UpT> {
+        let value = value.as_ref()?;
+        let mut ctx = Self::fold_expr(down, value).unwrap_or_else(|| down.fork());
+        Assembler::guard(&mut ctx, value.span);
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::RET));
+        Some(ctx)
+    }
 
     // A declaration sets up a scope-local variable
     fn fold_declaration(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
-        let mut down = down.clone();
+        let mut down = down.fork();
         down.declarations.push(id.id.clone());
         Some(down)
     }
 
+    // A function is compiled out of line into its own section. CALL hands the
+    // arguments over on a fresh stack frame, so the prologue binds them into a
+    // new environment frame, last parameter first. Falling off the end returns Nil.
+    // Only the function's own frames and the global one are in view inside of it.
+    fn fold_fundecl(
+        down: &Self::DownT,
+        id: &Identifier,
+        params: &[Identifier],
+        body: &Stmt,
+    ) -> Option<Self::UpT> {
+        let inner = AsmCtx {
+            base: id.id.clone(),
+            suffix: 0,
+            ..down.fork()
+        };
+        let mut body = Self::fold_stmt(&inner, body).unwrap_or_default();
+        Assembler::return_nil(&mut body, id.span);
+
+        let mut blocks = Vec::new();
+        blocks.push(MetaInst::Span(id.span));
+        blocks.push(MetaInst::ByteCode(ByteCode::PUSHE));
+        blocks.push(MetaInst::Operand(params.len()));
+        for idx in (0..params.len()).rev() {
+            blocks.push(MetaInst::ByteCode(ByteCode::STOREV));
+            blocks.push(MetaInst::Operand(0));
+            blocks.push(MetaInst::Operand(idx));
+            blocks.push(MetaInst::ByteCode(ByteCode::POP));
+        }
+        blocks.extend(body.blocks);
+
+        let mut ctx = down.fork();
+        ctx.functions.push((id.id.clone(), params.len(), blocks));
+        ctx.functions.extend(body.functions);
+        Some(ctx)
+    }
+
     // The LHS will become a location in the environment (e.g. 0, 0). The RHS is a value,
     // which will be stored at this location.
-    fn fold_assignment(down: &Self::DownT, lhs: &Box<Expr>, rhs: &Box<Expr>) -> Option<Self::UpT> {
-        let children = [rhs, lhs]
-            .iter()
-            .map(|e| Self::fold_expr(down, e))
-            .collect();
-
-        let mut ctx = Self::reduce(children).unwrap();
-        ctx.blocks.push(MetaInst::ByteCode(ByteCode::STOREV));
-        // TODO: StoreV needs to get the arguments as to where to store the value here
+    fn fold_assignment(down: &Self::DownT, lhs: &Expr, rhs: &Expr) -> Option<Self::UpT> {
+        let mut ctx = Self::fold_expr(down, rhs).unwrap_or_else(|| down.fork());
+        Assembler::guard(&mut ctx, rhs.span);
+        match &lhs.kind {
+            ExprKind::Id(id) => Assembler::variable(&mut ctx, id, true)?,
+            ExprKind::Dot(this, field) => {
+                let field = match &field.kind {
+                    ExprKind::Id(field) => field,
+                    _ => return None,
+                };
+                Assembler::variable(&mut ctx, this, false)?;
+                ctx.blocks.push(MetaInst::Span(lhs.span));
+                ctx.blocks.push(MetaInst::ByteCode(ByteCode::STOREF));
+                ctx.blocks.push(MetaInst::Field(field.id.clone()));
+            }
+            ExprKind::Index(xs, idx) => {
+                let children =
+                    Assembler::fold_seq(&ctx.fork(), &[xs, idx], |d, e| Self::fold_expr(d, e));
+                let target = Self::reduce(children)?;
+                ctx = Self::reduce(vec![Some(ctx), Some(target)])?;
+                ctx.blocks.push(MetaInst::Span(lhs.span));
+                ctx.blocks.push(MetaInst::ByteCode(ByteCode::STOREI));
+            }
+            // A bare procedure call statement is parsed as an assignment to Nil,
+            // so there is nothing to store to.
+            ExprKind::Literal(Literal::Nil) => {}
+            _ => return None,
+        }
         ctx.blocks.push(MetaInst::ByteCode(ByteCode::POP));
         Some(ctx)
     }
 
-    // Pushes an identifier instruction, which will resolve to the location of the declared
-    // identifier
+    // The code of every statement is marked with its span, so that the VM can point
+    // errors back at the source
+    fn fold_stmt(down: &Self::DownT, s: &Stmt) -> Option<Self::UpT> {
+        let mut ctx = match &s.kind {
+            StmtKind::Return(None) => {
+                let mut ctx = down.fork();
+                Assembler::return_nil(&mut ctx, s.span);
+                ctx
+            }
+            _ => Self::walk_stmt(down, s)?,
+        };
+        if !ctx.blocks.is_empty() {
+            ctx.blocks.insert(0, MetaInst::Span(s.span));
+        }
+        Some(ctx)
+    }
+
+    // An operation that can fail is marked more precisely with the span of its own
+    // expression. It comes after the code for its operands, as the last instruction
+    // of the expression.
+    fn fold_expr(down: &Self::DownT, e: &Expr) -> Option<Self::UpT> {
+        let mut ctx = Self::walk_expr(down, e)?;
+        match &e.kind {
+            ExprKind::Id(_) | ExprKind::Literal(_) => {}
+            ExprKind::BinaryOp(_, Opcode::And, _) | ExprKind::BinaryOp(_, Opcode::Or, _) => {}
+            _ => {
+                let last = ctx
+                    .blocks
+                    .iter()
+                    .rposition(|i| matches!(i, MetaInst::ByteCode(_)));
+                if let Some(idx) = last {
+                    ctx.blocks.insert(idx, MetaInst::Span(e.span));
+                }
+            }
+        }
+        Some(ctx)
+    }
+
     fn fold_id(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
-        let mut ctx = down.clone();
-        ctx.blocks.push(MetaInst::Identifier(id.id.clone()));
+        let mut ctx = down.fork();
+        Assembler::variable(&mut ctx, id, false)?;
+        Some(ctx)
+    }
+
+    // Both operands are pushed left to right, then the operator consumes them.
+    // Logical operators only evaluate the right operand when the left one doesn't
+    // already decide the result.
+    fn fold_binary(down: &Self::DownT, lhs: &Expr, op: &Opcode, rhs: &Expr) -> Option<Self::UpT> {
+        if let Opcode::And | Opcode::Or = op {
+            return Assembler::fold_logical(down, lhs, op, rhs);
+        }
+
+        let op = *BIN_OP_TO_BYTE.get(op)?;
+        let children = Assembler::fold_seq(down, &[lhs, rhs], |d, e| Self::fold_expr(d, e));
+        let mut ctx = Self::reduce(children)?;
+        ctx.blocks.push(MetaInst::ByteCode(op));
+        Some(ctx)
+    }
+
+    fn fold_unary(down: &Self::DownT, op: &Opcode, rhs: &Expr) -> Option<Self::UpT> {
+        let op = *UN_OP_TO_BYTE.get(op)?;
+        let mut ctx = Self::fold_expr(down, rhs)?;
+        ctx.blocks.push(MetaInst::ByteCode(op));
+        Some(ctx)
+    }
+
+    // Arguments are pushed left to right, then CALL moves them into the callee's frame
+    fn fold_funcall(down: &Self::DownT, id: &Identifier, args: &[Expr]) -> Option<Self::UpT> {
+        let children = Assembler::fold_seq(down, args, |d, e| {
+            let mut ctx = Self::fold_expr(d, e)?;
+            Assembler::guard(&mut ctx, e.span);
+            Some(ctx)
+        });
+        let mut ctx = Self::reduce(children).unwrap_or_else(|| down.fork());
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::CALL));
+        ctx.blocks.push(MetaInst::Label(id.id.clone()));
+        ctx.blocks.push(MetaInst::Operand(args.len()));
+        Some(ctx)
+    }
+
+    // Loads the struct and then the field out of it. A method call loads the object
+    // and its arguments, then CALLM hands them over to the object.
+    fn fold_dot(down: &Self::DownT, this: &Identifier, action: &Expr) -> Option<Self::UpT> {
+        let mut ctx = down.fork();
+        Assembler::variable(&mut ctx, this, false)?;
+        match &action.kind {
+            ExprKind::Id(field) => {
+                ctx.blocks.push(MetaInst::ByteCode(ByteCode::LOADF));
+                ctx.blocks.push(MetaInst::Field(field.id.clone()));
+            }
+            ExprKind::FunCall(method, args) => {
+                let children = Assembler::fold_seq(&ctx.fork(), args, Self::fold_expr);
+                ctx = Self::reduce(vec![Some(ctx)].into_iter().chain(children).collect())?;
+                ctx.blocks.push(MetaInst::ByteCode(ByteCode::CALLM));
+                ctx.blocks.push(MetaInst::Field(method.id.clone()));
+                ctx.blocks.push(MetaInst::Operand(args.len()));
+            }
+            _ => return None,
+        }
+        Some(ctx)
+    }
+
+    // Every element is pushed in order, then collected into a new list
+    fn fold_list(down: &Self::DownT, xs: &[Box<Expr>]) -> Option<Self::UpT> {
+        let children = Assembler::fold_seq(down, xs, |d, e| Self::fold_expr(d, e));
+        let mut ctx = Self::reduce(children).unwrap_or_else(|| down.fork());
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::LIST));
+        ctx.blocks.push(MetaInst::Operand(xs.len()));
+        Some(ctx)
+    }
+
+    fn fold_index(down: &Self::DownT, xs: &Expr, idx: &Expr) -> Option<Self::UpT> {
+        let children = Assembler::fold_seq(down, &[xs, idx], |d, e| Self::fold_expr(d, e));
+        let mut ctx = Self::reduce(children)?;
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::LOADI));
         Some(ctx)
     }
 
     fn fold_literal(down: &Self::DownT, lit: &Literal) -> Option<Self::UpT> {
-        let mut ctx = down.clone();
+        let mut ctx = down.fork();
 
         // TODO: It is a bit strange that there are two different literal types, and
         //  in the future, this should be combined into one.
         let data = match lit {
-            Literal::Number(n) => EstaData::new_int(*n as i32),
+            Literal::Number(n) => EstaData::new_int(*n),
             Literal::Boolean(b) => EstaData::new_bool(*b),
-            _ => Default::default(),
+            Literal::String(s) => EstaData::new_str(s.clone()),
+            Literal::Nil => Default::default(),
         };
 
+        ctx.blocks.push(MetaInst::ByteCode(ByteCode::LOADC));
         ctx.blocks.push(MetaInst::Const(data));
 
         Some(ctx)
     }
 }
-
-//fn dispatch_stmt(ctx: &mut AsmCtx, s: &Stmt) -> DispatchRet {
-//    match s {
-//        Stmt::Block(body, is_scope) => make_block(ctx, body, is_scope),
-//        Stmt::If(test, body, alter) => make_if(ctx, test, body, alter),
-//        Stmt::While(test, body) => make_while(ctx, test, body),
-//        Stmt::Return(value) => make_return(ctx, value),
-//        Stmt::Declaration(id) => make_declaration(ctx, id),
-//        Stmt::FunDecl(id, args, body) => make_fun(ctx, id, &args, body),
-//        Stmt::Assignment(lhs, rhs) => make_assignment(ctx, lhs, rhs),
-//        Stmt::Struct(id, fields) => make_struct(ctx, id, fields),
-//    }
-//}
-//
-//fn dispatch_expr(ctx: &mut AsmCtx, e: &Expr, l_value: bool) -> DispatchRet {
-//    match e {
-//        Expr::Id(id) => make_identifier(ctx, id, l_value),
-//        Expr::Literal(lit) => make_literal(ctx, &lit),
-//        Expr::BinaryOp(lhs, op, rhs) => make_binary(ctx, &lhs, &op, &rhs),
-//        Expr::UnaryOp(op, rhs) => make_unary(ctx, &op, rhs),
-//        Expr::FunCall(id, args) => make_funcall(ctx, &id, &args),
-//        Expr::List(xs) => make_list(ctx, &xs),
-//        Expr::Dot(this, action) => make_dot(ctx, &this, &action),
-//    }
-//}
-//
-//fn make_if(ctx: &mut AsmCtx, test: &Box<Expr>, body: &Box<Stmt>, alter: &Box<Stmt>) -> DispatchRet {
-//    let alter_lbl = ctx.next_label();
-//    let cont_lbl = ctx.next_label();
-//
-//    let test = dispatch_expr(ctx, test, false)?;
-//    let body = dispatch_stmt(ctx, body)?;
-//    let alter = dispatch_stmt(ctx, alter)?;
-//
-//    let mut insts = Vec::new();
-//    insts.extend(test);
-//    insts.push(MetaAsm::Inst(MetaInst::new_label(
-//        ByteCode::JUMPZ,
-//        alter_lbl.clone(),
-//    )));
-//    insts.extend(body);
-//    insts.push(MetaAsm::Inst(MetaInst::new_label(
-//        ByteCode::JUMP,
-//        cont_lbl.clone(),
-//    )));
-//    insts.push(MetaAsm::Lbl(alter_lbl.clone()));
-//    insts.extend(alter);
-//    insts.push(MetaAsm::Lbl(cont_lbl.clone()));
-//    Ok(insts)
-//}
-//
-//fn make_while(ctx: &mut AsmCtx, test: &Box<Expr>, body: &Box<Stmt>) -> DispatchRet {
-//    let test_lbl = ctx.next_label();
-//    let cont_lbl = ctx.next_label();
-//
-//    let test = dispatch_expr(ctx, test, false)?;
-//    let body = dispatch_stmt(ctx, body)?;
-//
-//    let mut insts = Vec::new();
-//    insts.push(MetaAsm::Lbl(test_lbl.clone()));
-//    insts.extend(test);
-//    insts.push(MetaAsm::Inst(MetaInst::new_label(
-//        ByteCode::JUMPZ,
-//        cont_lbl.clone(),
-//    )));
-//    insts.extend(body);
-//    insts.push(MetaAsm::Inst(MetaInst::new_label(
-//        ByteCode::JUMP,
-//        test_lbl.clone(),
-//    )));
-//    insts.push(MetaAsm::Lbl(cont_lbl.clone()));
-//    Ok(insts)
-//}
-//
-//fn make_return(ctx: &mut AsmCtx, value: &Option<Box<Expr>>) -> DispatchRet {
-//    let mut insts = Vec::new();
-//    match value {
-//        Some(rhs) => {
-//            let rhs = dispatch_expr(ctx, rhs, false)?;
-//            insts.extend(rhs);
-//            insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADRC, -3)));
-//            insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::STORE)));
-//            insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::RET, 2)));
-//        }
-//        None => {
-//            insts.push(MetaAsm::Inst(MetaInst::new_data(
-//                ByteCode::RET,
-//                ctx.args as i64 + 2,
-//            )));
-//        }
-//    };
-//
-//    Ok(insts)
-//}
-//
-//fn make_declaration(ctx: &mut AsmCtx, id: &Identifier) -> DispatchRet {
-//    // TODO: Make this better
-//    ctx.define(&id.id);
-//    Ok(Vec::new())
-//}
-//
-//fn make_fun(
-//    ctx: &mut AsmCtx,
-//    id: &Identifier,
-//    args: &Vec<Identifier>,
-//    body: &Box<Stmt>,
-//) -> DispatchRet {
-//    ctx.add_fun(&id.id);
-//    ctx.args = args.len(); // TODO: Do we need this?
-//    let mut insts = Vec::new();
-//    insts.push(MetaAsm::Lbl(id.id.clone()));
-//
-//    for id in args {
-//        ctx.define_arg(&id.id);
-//    }
-//
-//    insts.push(MetaAsm::Inst(MetaInst::new_local_alloc(
-//        ByteCode::ALLOC,
-//        id.id.clone(),
-//    )));
-//
-//    insts.extend(dispatch_stmt(ctx, body)?);
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::RET, 2)));
-//
-//    ctx.pop_fun();
-//    Ok(insts)
-//}
-//
-//fn make_assignment(ctx: &mut AsmCtx, lhs: &Box<Expr>, rhs: &Box<Expr>) -> DispatchRet {
-//    let rhs = dispatch_expr(ctx, rhs, false)?;
-//    let lhs = dispatch_expr(ctx, lhs, true)?;
-//
-//    let mut insts = Vec::new();
-//    insts.extend(rhs);
-//    insts.extend(lhs);
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::STORE)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::POP)));
-//
-//    Ok(insts)
-//}
-//
-//// Create a new constructor function for this struct which will allocate space
-//// on the heap with the right tags and return the address to the caller
-//fn make_struct(ctx: &mut AsmCtx, id: &String, fields: &Vec<Identifier>) -> DispatchRet {
-//    ctx.add_fun(id);
-//    let mut insts = Vec::new();
-//
-//    let esta_struct = ctx.get_esta_struct(id).unwrap();
-//    insts.push(MetaAsm::Lbl(id.clone()));
-//
-//    // Alloc new space on the heap to store the struct
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(
-//        ByteCode::LOADC,
-//        esta_struct.size as i64,
-//    )));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::NEW)));
-//
-//    // Set the first space to the struct's tag
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(
-//        ByteCode::LOADC,
-//        esta_struct.tag as i64,
-//    )));
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(
-//        ByteCode::LOADRC,
-//        0 as i64,
-//    )));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::LOAD)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADC, 0 as i64)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::ADD)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::STOREH)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::POP)));
-//
-//    // Set the second space to the struct's size
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(
-//        ByteCode::LOADC,
-//        esta_struct.size as i64,
-//    )));
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(
-//        ByteCode::LOADRC,
-//        0 as i64,
-//    )));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::LOAD)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADC, 1 as i64)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::ADD)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::STOREH)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::POP)));
-//
-//    // Return the starting address of the struct
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADRC, -3)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::STORE)));
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::RET, 2)));
-//
-//    ctx.pop_fun();
-//    Ok(insts)
-//}
-//
-//fn make_identifier(ctx: &mut AsmCtx, id: &Identifier, l_value: bool) -> DispatchRet {
-//    let mut insts = Vec::new();
-//    debug!("Making: {}", &id.id);
-//    let offset = ctx.get(&id.id)? as i64;
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADRC, offset)));
-//    if !l_value {
-//        insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::LOAD)));
-//    }
-//    Ok(insts)
-//}
-//
-//fn make_literal(_ctx: &mut AsmCtx, lit: &Literal) -> DispatchRet {
-//    let insts = match lit {
-//        Literal::Number(num) => vec![MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADC, *num))],
-//        Literal::Boolean(bool) => vec![MetaAsm::Inst(MetaInst::new_data(
-//            ByteCode::LOADC,
-//            bool_to_i64(*bool),
-//        ))],
-//        _ => Vec::new(),
-//    };
-//    Ok(insts)
-//}
-//
-//fn make_binary(ctx: &mut AsmCtx, lhs: &Box<Expr>, op: &Opcode, rhs: &Box<Expr>) -> DispatchRet {
-//    let lhs = dispatch_expr(ctx, lhs, false)?;
-//    let rhs = dispatch_expr(ctx, rhs, false)?;
-//    let op: ByteCode = BIN_OP_TO_BYTE.get(op).unwrap().clone();
-//    let mut insts = Vec::new();
-//    insts.extend(lhs);
-//    insts.extend(rhs);
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(op)));
-//    Ok(insts)
-//}
-//
-//fn make_unary(ctx: &mut AsmCtx, op: &Opcode, rhs: &Box<Expr>) -> DispatchRet {
-//    let rhs = dispatch_expr(ctx, rhs, false)?;
-//    let op: ByteCode = UN_OP_TO_BYTE.get(op).unwrap().clone();
-//    let mut insts = Vec::new();
-//    insts.extend(rhs);
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(op)));
-//    Ok(insts)
-//}
-//
-//fn make_funcall(ctx: &mut AsmCtx, id: &String, args: &Vec<Expr>) -> DispatchRet {
-//    let mut insts = Vec::new();
-//
-//    // Allocate space for the return value
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::ALLOC, 1)));
-//
-//    // Push arguments to stack from right to left (e.g. backwards)
-//    for arg in args.iter().rev() {
-//        let rhs = dispatch_expr(ctx, arg, false)?;
-//        insts.extend(rhs);
-//    }
-//
-//    // Save FP
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::MARK)));
-//    // Calculate the starting address of the called function
-//    insts.push(MetaAsm::Inst(MetaInst::new_label(
-//        ByteCode::LOADC,
-//        id.clone(),
-//    )));
-//    // Pass control over to the called function
-//    insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::CALL)));
-//
-//    // Once the called function returns, slide the return value to the top of the stack
-//    insts.push(MetaAsm::Inst(MetaInst::new_data(
-//        ByteCode::SLIDE,
-//        args.len() as i64,
-//    )));
-//
-//    Ok(insts)
-//}
-//
-//fn make_list(ctx: &mut AsmCtx, xs: &Vec<Box<Expr>>) -> DispatchRet {
-//    Ok(Vec::new())
-//}
-//
-//fn make_dot(ctx: &mut AsmCtx, this: &Identifier, action: &Box<Expr>) -> DispatchRet {
-//    // TODO: Right now, this only works for accessing values
-//    match *action.clone() {
-//        Expr::Id(identifier) => {}
-//        _ => {}
-//    }
-//    //    let mut insts = Vec::new();
-//    //    debug!("Making: {}", &id.id);
-//    //    let offset = ctx.get(&id.id)? as i64;
-//    //    insts.push(MetaAsm::Inst(MetaInst::new_data(ByteCode::LOADRC, offset)));
-//    //    if !l_value {
-//    //        insts.push(MetaAsm::Inst(MetaInst::new_inst(ByteCode::LOAD)));
-//    //    }
-//    //    Ok(insts)
-//
-//    Ok(Vec::new())
-//}
-//
-//fn bootstrap_startup(insts: Vec<MetaAsm>) -> Vec<MetaAsm> {
-//    let mut prelude = vec![
-//        MetaAsm::Inst(MetaInst::new_data(ByteCode::ALLOC, 1)),
-//        MetaAsm::Inst(MetaInst::new_inst(ByteCode::MARK)),
-//        MetaAsm::Inst(MetaInst::new_label(ByteCode::LOADC, "main".to_string())),
-//        MetaAsm::Inst(MetaInst::new_inst(ByteCode::CALL)),
-//        MetaAsm::Inst(MetaInst::new_inst(ByteCode::HALT)),
-//    ];
-//    prelude.extend(insts);
-//    prelude
-//}
-//
-//fn assemble(insts: Vec<MetaAsm>, ctx: &AsmCtx) -> Vec<Inst> {
-//    let mut labels = HashMap::new();
-//    let insts = insts.iter().fold(Vec::new(), |mut v, i| match i {
-//        MetaAsm::Inst(i) => {
-//            v.push(i);
-//            v
-//        }
-//        MetaAsm::Lbl(l) => {
-//            labels.insert(l, v.len());
-//            v
-//        }
-//    });
-//    insts
-//        .iter()
-//        .map(|i| match i.var.clone() {
-//            MetaVar::Data(d) => Inst::new_data(i.inst.clone(), d),
-//            MetaVar::Label(l) => Inst::new_data(
-//                i.inst.clone(),
-//                *labels.get(&l).expect("Couldn't find label") as i64,
-//            ),
-//            MetaVar::LocalAlloc(id) => Inst::new_data(
-//                i.inst.clone(),
-//                *ctx.locals.get(&id).expect("Couldn't find local") as i64,
-//            ),
-//            MetaVar::None => Inst::new_inst(i.inst.clone()),
-//        })
-//        .collect()
-//}
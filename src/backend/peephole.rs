@@ -0,0 +1,216 @@
+use crate::error::Span;
+use crate::vm::bytecode::*;
+use std::collections::HashSet;
+
+/// Instruction counts of a program before and after the peephole pass
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counts {
+    pub before: usize,
+    pub after: usize,
+}
+
+/// An instruction along with its arguments, a label marking a jump destination,
+/// or the span of the source the following instructions were compiled from
+#[derive(Debug, Clone, PartialEq)]
+enum Item {
+    Op(ByteCode, Vec<MetaInst>),
+    Marker(String),
+    Span(Span),
+}
+
+/// # Peephole Optimizer
+///
+/// Cleans up the patterns the assembler leaves behind in a section of code,
+/// which is either the startup prelude or a single function body:
+///
+/// - A store followed by a POP becomes a single POPV/POPG, and if the same
+///   variable is loaded right after, the value is left on the stack instead.
+/// - Loading the same thing twice in a row becomes a load and a DUP.
+/// - Scopes that declare no variables don't push an environment frame. Variables
+///   further out are now a frame closer to any code inside of those scopes.
+/// - A JUMP to the instruction right after it is removed.
+///
+/// Patterns never reach across a label, since code may jump in between. Spans
+/// don't get in the way of a pattern.
+pub struct Peephole;
+
+impl Peephole {
+    pub fn optimize(blocks: Vec<MetaInst>) -> Vec<MetaInst> {
+        let mut items = Peephole::split(blocks);
+        loop {
+            let len = items.len();
+            items = Peephole::fuse(items);
+            items = Peephole::drop_empty_frames(items);
+            items = Peephole::drop_jumps_to_next(items);
+            if items.len() == len {
+                break;
+            }
+        }
+        Peephole::join(items)
+    }
+
+    pub fn count(blocks: &[MetaInst]) -> usize {
+        blocks
+            .iter()
+            .filter(|i| matches!(i, MetaInst::ByteCode(_)))
+            .count()
+    }
+
+    fn split(blocks: Vec<MetaInst>) -> Vec<Item> {
+        let mut items = Vec::new();
+        for inst in blocks {
+            match (inst, items.last_mut()) {
+                (MetaInst::ByteCode(b), _) => items.push(Item::Op(b, Vec::new())),
+                (MetaInst::Label(l), Some(Item::Op(b, args)))
+                    if args.len() < BYTECODE_ARITY[b] as usize =>
+                {
+                    args.push(MetaInst::Label(l))
+                }
+                (MetaInst::Label(l), _) => items.push(Item::Marker(l)),
+                (MetaInst::Span(s), _) => items.push(Item::Span(s)),
+                (arg, Some(Item::Op(_, args))) => args.push(arg),
+                (arg, _) => panic!("Argument {:?} without an instruction", arg),
+            }
+        }
+        items
+    }
+
+    fn join(items: Vec<Item>) -> Vec<MetaInst> {
+        items
+            .into_iter()
+            .flat_map(|item| match item {
+                Item::Op(b, args) => {
+                    let mut insts = vec![MetaInst::ByteCode(b)];
+                    insts.extend(args);
+                    insts
+                }
+                Item::Marker(l) => vec![MetaInst::Label(l)],
+                Item::Span(s) => vec![MetaInst::Span(s)],
+            })
+            .collect()
+    }
+
+    // Every item is pushed onto the output and the end of the output is
+    // rewritten for as long as it matches one of the patterns. Spans are held back
+    // until then and go in front of whatever the item was rewritten into.
+    fn fuse(items: Vec<Item>) -> Vec<Item> {
+        use self::ByteCode::*;
+        let mut out: Vec<Item> = Vec::new();
+        let mut spans = Vec::new();
+        for item in items {
+            if let Item::Span(_) = item {
+                spans.push(item);
+                continue;
+            }
+            out.push(item);
+            loop {
+                let n = out.len();
+                let rewrite = match &out[n.saturating_sub(2)..] {
+                    [Item::Op(STOREV, a), Item::Op(POP, _)] => Some(Item::Op(POPV, a.clone())),
+                    [Item::Op(STOREG, a), Item::Op(POP, _)] => Some(Item::Op(POPG, a.clone())),
+                    [Item::Op(POPV, a), Item::Op(LOADV, b)] if a == b => {
+                        Some(Item::Op(STOREV, a.clone()))
+                    }
+                    [Item::Op(POPG, a), Item::Op(LOADG, b)] if a == b => {
+                        Some(Item::Op(STOREG, a.clone()))
+                    }
+                    [Item::Op(x @ LOADV, a), Item::Op(y, b)]
+                    | [Item::Op(x @ LOADG, a), Item::Op(y, b)]
+                    | [Item::Op(x @ LOADC, a), Item::Op(y, b)]
+                        if x == y && a == b =>
+                    {
+                        out[n - 1] = Item::Op(DUP, Vec::new());
+                        None
+                    }
+                    _ => None,
+                };
+                match rewrite {
+                    Some(item) => {
+                        out.truncate(n - 2);
+                        out.push(item);
+                    }
+                    None => break,
+                }
+            }
+            let op = out.pop().unwrap();
+            out.append(&mut spans);
+            out.push(op);
+        }
+        out.append(&mut spans);
+        out
+    }
+
+    // Scopes are emitted as a PUSHE and a POPE around their code, so they nest
+    // like brackets. A PUSHE without a POPE, like a function's, is left alone.
+    fn drop_empty_frames(items: Vec<Item>) -> Vec<Item> {
+        let mut open = Vec::new();
+        let mut empty = HashSet::new();
+        for (idx, item) in items.iter().enumerate() {
+            match item {
+                Item::Op(ByteCode::PUSHE, args) => {
+                    open.push((idx, args[..] == [MetaInst::Operand(0)]));
+                }
+                Item::Op(ByteCode::POPE, _) => {
+                    if let Some((push, true)) = open.pop() {
+                        empty.insert(push);
+                        empty.insert(idx);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if empty.is_empty() {
+            return items;
+        }
+
+        // Whether each frame currently pushed, innermost last, is being dropped
+        let mut dropped: Vec<bool> = Vec::new();
+        let mut out = Vec::new();
+        for (idx, item) in items.into_iter().enumerate() {
+            match item {
+                Item::Op(ByteCode::PUSHE, _) => dropped.push(empty.contains(&idx)),
+                Item::Op(ByteCode::POPE, _) => {
+                    dropped.pop();
+                }
+                Item::Op(op @ ByteCode::LOADV, args)
+                | Item::Op(op @ ByteCode::STOREV, args)
+                | Item::Op(op @ ByteCode::POPV, args) => {
+                    let args = match &args[..] {
+                        [MetaInst::Operand(depth), index] => {
+                            let skipped = dropped.iter().rev().take(*depth).filter(|d| **d).count();
+                            vec![MetaInst::Operand(*depth - skipped), index.clone()]
+                        }
+                        _ => args,
+                    };
+                    out.push(Item::Op(op, args));
+                    continue;
+                }
+                _ => {}
+            }
+            if !empty.contains(&idx) {
+                out.push(item);
+            }
+        }
+        out
+    }
+
+    fn drop_jumps_to_next(items: Vec<Item>) -> Vec<Item> {
+        let mut out = Vec::new();
+        for (idx, item) in items.iter().enumerate() {
+            if let Item::Op(ByteCode::JUMP, args) = item {
+                let to_next = items[idx + 1..]
+                    .iter()
+                    .take_while(|i| matches!(i, Item::Marker(_) | Item::Span(_)))
+                    .any(|i| match (i, &args[..]) {
+                        (Item::Marker(l), [MetaInst::Label(target)]) => l == target,
+                        _ => false,
+                    });
+                if to_next {
+                    continue;
+                }
+            }
+            out.push(item.clone());
+        }
+        out
+    }
+}
@@ -1,16 +1,27 @@
+use crate::backend::peephole::Counts;
+use crate::error::{EstaError, Span};
+use crate::frontend::ast::EstaStruct;
+use crate::middleend::resolver::Slot;
 use crate::vm::bytecode::*;
-use crate::vm::EstaData;
-use itertools::Itertools;
+use crate::vm::{EstaData, EstaKind};
 use std::collections::HashMap;
+use std::rc::Rc;
 
 /// Program
 ///
 /// This struct contains all information necessary to run an Esta program
 /// TODO: Integrate with Serde so programs can be stored and loaded
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Program {
     pub insts: Vec<u8>,
     pub consts: Vec<EstaData>,
+    pub functions: HashMap<String, (usize, usize)>, // Entry point and arity of every function
+    pub structs: Vec<EstaStruct>,                   // Layout of every struct, by tag
+    pub fields: Vec<String>,                        // Names of every field and method accessed
+    pub natives: Vec<String>,                       // Names of every native function called
+    pub globals: Vec<String>,                       // Names of every global variable, by index
+    pub lines: Vec<(usize, Span)>,                  // Source span of the code from each offset on
+    pub peephole: Counts, // Instruction counts before and after the peephole pass
 }
 
 /// Assembly Context
@@ -24,6 +35,9 @@ pub struct AsmCtx {
     pub blocks: Vec<MetaInst>,
     pub suffix: usize,
     pub declarations: Vec<String>, // Vec of local variables names declared in scope
+    pub functions: Vec<(String, usize, Vec<MetaInst>)>, // Compiled function bodies and their arity, by name
+    pub slots: Rc<HashMap<Span, Slot>>, // Where every variable use lives, from the resolver
+    pub guards: Rc<HashMap<Span, (EstaKind, usize)>>, // Kind and struct tag every guarded value must have
 }
 
 impl AsmCtx {
@@ -34,115 +48,220 @@ impl AsmCtx {
         }
     }
 
-    pub fn assemble(self) -> Program {
-        let (consts, consts_map) = AsmCtx::make_consts(&self.blocks);
-
-        let blocks = AsmCtx::resolve_identifiers(self.blocks);
+    pub fn assemble(self) -> Result<Program, EstaError> {
+        let mut blocks = self.blocks;
+        for (name, _, body) in self.functions.iter() {
+            blocks.push(MetaInst::Label(name.clone()));
+            blocks.extend(body.iter().cloned());
+        }
 
-        let insts = blocks
-            .iter()
-            .map(|i| match i {
-                MetaInst::ByteCode(b) => vec![b.clone().into()],
-                MetaInst::Number(n) => n.to_le_bytes().to_vec(),
-                MetaInst::Label(_) => panic!("Label found in processed bytecode"),
-                MetaInst::Const(c) => {
-                    let offset = consts_map.get(c).unwrap().clone() as i16;
-                    offset.to_le_bytes().to_vec()
-                }
-                MetaInst::Identifier(_) => panic!("Identifier found in processed bytecode"),
-                MetaInst::Declaration(_) => panic!("Declaration found in processed bytecode"),
+        let (consts, consts_map) = AsmCtx::make_consts(&blocks);
+        let (fields, fields_map) = AsmCtx::make_names(&blocks, |i| match i {
+            MetaInst::Field(f) => Some(f),
+            _ => None,
+        });
+        let (natives, natives_map) = AsmCtx::make_names(&blocks, |i| match i {
+            MetaInst::Native(n) => Some(n),
+            _ => None,
+        });
+        let blocks = AsmCtx::place_consts(blocks, &consts_map);
+        let (blocks, labels) = AsmCtx::resolve_labels(blocks)?;
+        let (blocks, lines) = AsmCtx::make_lines(blocks);
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|(name, arity, _)| {
+                let offset = labels[&name];
+                (name, (offset, arity))
             })
-            .flatten()
             .collect();
 
-        Program { insts, consts }
+        let mut insts = Vec::with_capacity(blocks.len());
+        for inst in blocks.iter() {
+            let number = match inst {
+                MetaInst::ByteCode(b) => {
+                    insts.push((*b).into());
+                    continue;
+                }
+                MetaInst::Number(n) => *n,
+                MetaInst::Operand(n) => AsmCtx::operand(*n)?,
+                MetaInst::Label(_) => panic!("Label found in processed bytecode"),
+                MetaInst::Const(_) => panic!("Constant found in processed bytecode"),
+                MetaInst::Span(_) => panic!("Span found in processed bytecode"),
+                MetaInst::Field(f) => AsmCtx::operand(fields_map[f])?,
+                MetaInst::Native(n) => AsmCtx::operand(natives_map[n])?,
+            };
+            insts.extend_from_slice(&number.to_le_bytes());
+        }
+
+        Ok(Program {
+            insts,
+            consts,
+            functions,
+            fields,
+            natives,
+            lines,
+            ..Default::default()
+        })
     }
 
-    // Scan the bytecode and create a consts section from every const bytecode
-    fn make_consts(blocks: &Vec<MetaInst>) -> (Vec<EstaData>, HashMap<EstaData, usize>) {
-        // TODO: Sort and dedup to remove unnecessary duplicates
-        let consts = blocks
-            .iter()
-            .filter_map(|i| match i {
-                MetaInst::Const(d) => Some(d),
-                _ => None,
-            })
-            .cloned()
-            .collect::<Vec<EstaData>>();
+    // The VM reads every argument back as an unsigned 16 bit number, so offsets
+    // and indices past u16::MAX can't be encoded
+    fn operand(n: usize) -> Result<i16, EstaError> {
+        if n > u16::MAX as usize {
+            Err(EstaError::compile("Program is too large"))
+        } else {
+            Ok(n as u16 as i16)
+        }
+    }
 
-        let consts_map: HashMap<EstaData, usize> = consts
-            .iter()
-            .enumerate()
-            .map(|(i, d)| (d.clone(), i))
-            .collect();
+    // Scan the bytecode and create a consts section holding every distinct constant
+    // once, in order of first use. EstaData may hold a host object, whose interior
+    // mutability clippy flags in a key, but constants can never be host objects.
+    #[allow(clippy::mutable_key_type)]
+    fn make_consts(blocks: &[MetaInst]) -> (Vec<EstaData>, HashMap<EstaData, usize>) {
+        let mut consts = Vec::new();
+        let mut consts_map = HashMap::new();
+        for data in blocks.iter().filter_map(|i| match i {
+            MetaInst::Const(d) => Some(d),
+            _ => None,
+        }) {
+            if !consts_map.contains_key(data) {
+                consts_map.insert(data.clone(), consts.len());
+                consts.push(data.clone());
+            }
+        }
         (consts, consts_map)
     }
 
-    // For each identifier, map the declaration's stack offset and declaration offset
-    // This in effect transforms bytecode like:
-    // > LOADV foo
-    // to
-    // > LOADV 2 3
-    // where foo is the third local variable declared two stacks away
-    fn resolve_identifiers(blocks: Vec<MetaInst>) -> Vec<MetaInst> {
-        // Replace all identifiers with their offsets
-        let blocks: Vec<MetaInst> = blocks
-            .iter()
-            .enumerate()
-            .map(|(idx, inst)| -> Vec<MetaInst> {
-                match inst {
-                    MetaInst::Identifier(id) => AsmCtx::find_declaration(&blocks, idx, id),
-                    inst => vec![inst.clone()],
+    // Swap every constant for its index in the consts section. An index too large
+    // for a single argument is split over the two arguments of a LOADCW instead.
+    #[allow(clippy::mutable_key_type)]
+    fn place_consts(blocks: Vec<MetaInst>, consts_map: &HashMap<EstaData, usize>) -> Vec<MetaInst> {
+        let mut placed = Vec::with_capacity(blocks.len());
+        for inst in blocks {
+            match inst {
+                MetaInst::Const(c) => {
+                    let offset = consts_map[&c];
+                    if offset <= u16::MAX as usize {
+                        placed.push(MetaInst::Number(offset as u16 as i16));
+                    } else {
+                        // Only LOADC takes a constant, so it is the instruction before
+                        placed.pop();
+                        placed.push(MetaInst::ByteCode(ByteCode::LOADCW));
+                        placed.push(MetaInst::Number((offset >> 16) as u16 as i16));
+                        placed.push(MetaInst::Number(offset as u16 as i16));
+                    }
                 }
-            })
-            .flatten()
-            .collect();
+                inst => placed.push(inst),
+            }
+        }
+        placed
+    }
 
-        // Now get rid of all declarations because they are no longer necessary
-        let blocks: Vec<MetaInst> = blocks
-            .into_iter()
-            .filter_map(|inst| match inst {
-                MetaInst::Declaration(_) => None,
-                inst => Some(inst),
-            })
-            .collect();
+    // Scan the bytecode and give every distinct name picked out of it an index
+    fn make_names(
+        blocks: &[MetaInst],
+        pick: fn(&MetaInst) -> Option<&String>,
+    ) -> (Vec<String>, HashMap<String, usize>) {
+        let mut names = Vec::new();
+        let mut names_map = HashMap::new();
+        for name in blocks.iter().filter_map(pick) {
+            if !names_map.contains_key(name) {
+                names_map.insert(name.clone(), names.len());
+                names.push(name.clone());
+            }
+        }
+        (names, names_map)
+    }
 
-        blocks
+    // Labels do double duty: as the argument of a jump they name a destination,
+    // anywhere else they mark one. First record the byte offset of every marker,
+    // then drop the markers and swap every destination for its offset.
+    fn resolve_labels(
+        blocks: Vec<MetaInst>,
+    ) -> Result<(Vec<MetaInst>, HashMap<String, usize>), EstaError> {
+        let mut labels = HashMap::new();
+        let mut offset = 0;
+        let mut args = 0;
+        for inst in blocks.iter() {
+            match inst {
+                MetaInst::ByteCode(b) => {
+                    offset += 1;
+                    args = BYTECODE_ARITY[b];
+                }
+                MetaInst::Label(l) if args == 0 => {
+                    labels.insert(l.clone(), offset);
+                }
+                MetaInst::Span(_) => {}
+                _ => {
+                    offset += 2;
+                    args -= 1;
+                }
+            }
+        }
+
+        let mut args = 0;
+        let mut resolved = Vec::with_capacity(blocks.len());
+        for inst in blocks {
+            match inst {
+                MetaInst::ByteCode(b) => {
+                    args = BYTECODE_ARITY[&b];
+                    resolved.push(MetaInst::ByteCode(b));
+                }
+                MetaInst::Label(_) if args == 0 => {}
+                MetaInst::Span(span) => resolved.push(MetaInst::Span(span)),
+                MetaInst::Label(l) => {
+                    args -= 1;
+                    let offset = *labels.get(&l).expect("Couldn't find label");
+                    resolved.push(MetaInst::Number(AsmCtx::operand(offset)?));
+                }
+                inst => {
+                    args -= 1;
+                    resolved.push(inst);
+                }
+            }
+        }
+        Ok((resolved, labels))
     }
 
-    // This helper method looks for an id's declaration in the most recent stack.
-    fn find_declaration(blocks: &Vec<MetaInst>, idx: usize, id: &str) -> Vec<MetaInst> {
-        println!("Searching for {}", id);
-        let mut stack_offset = 0;
-        let mut decl_offset = 0;
-        for mut idx in (0..idx).rev() {
-            let inst = &blocks[idx];
+    // Drop the span markers, recording the byte offset each one marks. Offsets
+    // only ever grow, so the VM can binary search for the span of an instruction.
+    fn make_lines(blocks: Vec<MetaInst>) -> (Vec<MetaInst>, Vec<(usize, Span)>) {
+        let mut lines: Vec<(usize, Span)> = Vec::new();
+        let mut offset = 0;
+        let mut insts = Vec::with_capacity(blocks.len());
+        for inst in blocks {
             match inst {
-                MetaInst::Declaration(decl) if decl == id => {
-                    // Count how many declarations come before this one
-                    idx -= 1;
-                    while let MetaInst::Declaration(_) = &blocks[idx] {
-                        idx -= 1;
-                        decl_offset += 1;
+                MetaInst::Span(span) => {
+                    // Of several markers at the same offset, the last one applies
+                    if lines.last().map(|(o, _)| *o) == Some(offset) {
+                        lines.pop();
                     }
-
-                    return vec![
-                        MetaInst::Number(stack_offset),
-                        MetaInst::Number(decl_offset),
-                    ];
+                    lines.push((offset, span));
                 }
-                MetaInst::ByteCode(bc) if bc == &ByteCode::POPE => {
-                    stack_offset -= 1;
+                MetaInst::ByteCode(_) => {
+                    offset += 1;
+                    insts.push(inst);
                 }
-                MetaInst::ByteCode(bc) if bc == &ByteCode::PUSHE => {
-                    stack_offset += 1;
+                _ => {
+                    offset += 2;
+                    insts.push(inst);
                 }
-                _ => {}
             }
         }
+        (insts, lines)
+    }
 
-        error!("Couldn't find {}", id);
-        panic!("Declaration not found!");
+    /// Creates an empty context that carries on with this context's label numbering
+    pub fn fork(&self) -> AsmCtx {
+        AsmCtx {
+            base: self.base.clone(),
+            suffix: self.suffix,
+            slots: self.slots.clone(),
+            guards: self.guards.clone(),
+            ..Default::default()
+        }
     }
 
     pub fn next_label(&mut self) -> String {
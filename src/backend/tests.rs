@@ -0,0 +1,111 @@
+use crate::backend::peephole::Peephole;
+use crate::vm::bytecode::{ByteCode::*, MetaInst};
+
+fn op(b: crate::vm::bytecode::ByteCode) -> MetaInst {
+    MetaInst::ByteCode(b)
+}
+
+fn num(n: usize) -> MetaInst {
+    MetaInst::Operand(n)
+}
+
+fn label(l: &str) -> MetaInst {
+    MetaInst::Label(l.to_string())
+}
+
+#[test]
+fn test_peephole_stores() {
+    let blocks = vec![
+        op(STOREV),
+        num(0),
+        num(1),
+        op(POP),
+        op(LOADV),
+        num(0),
+        num(1),
+        op(STOREG),
+        num(2),
+        op(POP),
+        op(LOADG),
+        num(3),
+        op(LOADG),
+        num(3),
+    ];
+    let expected = vec![
+        op(STOREV),
+        num(0),
+        num(1),
+        op(POPG),
+        num(2),
+        op(LOADG),
+        num(3),
+        op(DUP),
+    ];
+    assert_eq!(Peephole::optimize(blocks), expected);
+
+    // Code may jump in between the store and the load
+    let blocks = vec![op(STOREV), num(0), num(1), op(POP), label("a")];
+    let blocks = [blocks, vec![op(LOADV), num(0), num(1)]].concat();
+    let expected = vec![
+        op(POPV),
+        num(0),
+        num(1),
+        label("a"),
+        op(LOADV),
+        num(0),
+        num(1),
+    ];
+    assert_eq!(Peephole::optimize(blocks), expected);
+}
+
+#[test]
+fn test_peephole_frames() {
+    // The middle frame is empty, so the variable in the outer one gets closer
+    let blocks = vec![
+        op(PUSHE),
+        num(1),
+        op(PUSHE),
+        num(0),
+        op(PUSHE),
+        num(1),
+        op(LOADV),
+        num(2),
+        num(0),
+        op(LOADV),
+        num(0),
+        num(0),
+        op(POPE),
+        op(POPE),
+        op(POPE),
+    ];
+    let expected = vec![
+        op(PUSHE),
+        num(1),
+        op(PUSHE),
+        num(1),
+        op(LOADV),
+        num(1),
+        num(0),
+        op(LOADV),
+        num(0),
+        num(0),
+        op(POPE),
+        op(POPE),
+    ];
+    assert_eq!(Peephole::optimize(blocks), expected);
+
+    // Removing the frame leaves a jump with nothing to jump over
+    let blocks = vec![
+        op(JUMP),
+        label("cont"),
+        label("alter"),
+        op(PUSHE),
+        num(0),
+        op(POPE),
+        label("cont"),
+        op(JUMP),
+        label("alter"),
+    ];
+    let expected = vec![label("alter"), label("cont"), op(JUMP), label("alter")];
+    assert_eq!(Peephole::optimize(blocks), expected);
+}
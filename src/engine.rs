@@ -0,0 +1,90 @@
+use crate::error::EstaError;
+use crate::middleend::Settings;
+use crate::vm::heap::Heap;
+use crate::vm::{CancelHandle, EstaData, Limits, VirtualMachine};
+use crate::{backend, frontend, middleend};
+
+/// # Engine
+///
+/// The way to embed Esta in a Rust program. An engine compiles a program once and
+/// keeps it loaded on its own VM, so the host can set globals, call functions
+/// and run main as often as it likes. Values cross over as EstaData, which plain
+/// Rust values convert into and back out of.
+///
+/// The top level statements of the program run the first time it is called into,
+/// so globals the host sets before then are visible to them. Should they fail,
+/// they run again from the start the next time it is called into. Globals the program
+/// itself initializes will overwrite what the host set. Type annotations are only
+/// checked where Esta code calls a function, not on arguments from the host.
+pub struct Engine {
+    vm: VirtualMachine,
+}
+
+impl Engine {
+    pub fn new(source: &str) -> Result<Engine, Vec<EstaError>> {
+        Engine::with_settings(source, &Default::default())
+    }
+
+    /// Compiles with settings, which must declare any natives the host is going
+    /// to register besides the builtins
+    pub fn with_settings(source: &str, settings: &Settings) -> Result<Engine, Vec<EstaError>> {
+        let stmts = frontend::run(source)?;
+        let (stmts, md) = middleend::run_with(stmts, settings)?;
+        for warning in md.warnings.iter() {
+            warn!("{}", warning);
+        }
+        let program = backend::generate(stmts, md)?;
+        Ok(Engine {
+            vm: VirtualMachine::new(program),
+        })
+    }
+
+    /// Makes a Rust function callable from the program, see VirtualMachine::register_native
+    pub fn register_native<F>(&mut self, name: &str, arity: Option<usize>, func: F)
+    where
+        F: FnMut(&mut Heap, Vec<EstaData>) -> Result<EstaData, EstaError> + 'static,
+    {
+        self.vm.register_native(name, arity, func);
+    }
+
+    pub fn set_global<T: Into<EstaData>>(&mut self, name: &str, value: T) -> Result<(), EstaError> {
+        self.vm.set_global(name, value.into())
+    }
+
+    pub fn global(&self, name: &str) -> Option<EstaData> {
+        self.vm.global(name)
+    }
+
+    /// Limits how many more instructions the program may run, see VirtualMachine::set_fuel
+    pub fn set_fuel(&mut self, fuel: Option<usize>) {
+        self.vm.set_fuel(fuel);
+    }
+
+    pub fn fuel(&self) -> Option<usize> {
+        self.vm.fuel()
+    }
+
+    /// Creates a handle that stops the program from another thread
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.vm.cancel_handle()
+    }
+
+    /// Limits the memory and call depth of the program, see VirtualMachine::set_limits
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.vm.set_limits(limits);
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.vm.memory_used()
+    }
+
+    /// Calls a function of the program by name and returns its result
+    pub fn call(&mut self, name: &str, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+        self.vm.call(name, args)
+    }
+
+    /// Runs the program as a whole and returns main's result
+    pub fn run(&mut self) -> Result<EstaData, EstaError> {
+        self.vm.run()
+    }
+}
@@ -0,0 +1,214 @@
+use std::error::Error;
+use std::fmt;
+
+/// The stage of the pipeline an error comes from, or why the host stopped the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Syntax,        // The source couldn't be parsed
+    Compile,       // The source parsed, but couldn't be turned into a program
+    Runtime,       // The VM failed while running the program
+    Warning,       // Nothing failed, but the source is likely not doing what was meant
+    OutOfFuel,     // The VM used up the steps it was allowed to run for
+    Cancelled,     // The VM was stopped through its cancel handle
+    MemoryLimit,   // The VM would have allocated more than its memory limit
+    StackOverflow, // The VM would have nested calls deeper than its call depth limit
+}
+
+/// Messages of the limits the VM reports as bare messages, see From<&'static str>
+pub const MEMORY_LIMIT: &str = "Memory limit exceeded";
+pub const STACK_OVERFLOW: &str = "Stack overflow";
+
+/// A position in the source. Lines and columns both count from 1 and columns
+/// count characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The stretch of source a node or error covers, from start up to but not including end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    /// Creates the smallest span covering both self and other
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// The parser reports locations as byte offsets into the source. A SourceMap
+/// remembers where every line starts, so it can quickly turn them into positions.
+pub struct SourceMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> SourceMap<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        SourceMap {
+            source,
+            line_starts,
+        }
+    }
+
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.source.len());
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let line_start = self.line_starts[line];
+        Position {
+            line: line + 1,
+            column: self.source[line_start..offset].chars().count() + 1,
+        }
+    }
+
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        Span {
+            start: self.position(start),
+            end: self.position(end),
+        }
+    }
+}
+
+/// # Esta Errors
+///
+/// Every stage, from parsing to running the VM, reports failures as an EstaError.
+/// Errors found in the source carry the span they were found at, which render()
+/// uses to point at the offending code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EstaError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl EstaError {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> EstaError {
+        EstaError {
+            kind,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn syntax<S: Into<String>>(message: S, span: Span) -> EstaError {
+        EstaError::new(ErrorKind::Syntax, message).with_span(span)
+    }
+
+    pub fn compile<S: Into<String>>(message: S) -> EstaError {
+        EstaError::new(ErrorKind::Compile, message)
+    }
+
+    pub fn runtime<S: Into<String>>(message: S) -> EstaError {
+        EstaError::new(ErrorKind::Runtime, message)
+    }
+
+    pub fn with_span(mut self, span: Span) -> EstaError {
+        self.span = Some(span);
+        self
+    }
+
+    /// Renders the error followed by the line of source it is about, with the
+    /// error's span underlined by carets:
+    /// > Syntax error: Unexpected token `)`
+    /// >  --> 1:9
+    /// >   |
+    /// > 1 | var a = );
+    /// >   |         ^
+    pub fn render(&self, source: &str) -> String {
+        let span = match self.span {
+            Some(span) => span,
+            None => return self.to_string(),
+        };
+        let line = source.lines().nth(span.start.line - 1).unwrap_or("");
+        let number = span.start.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // Tabs are kept so that the carets line up with the source above them
+        let indent: String = line
+            .chars()
+            .take(span.start.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = if span.end.line == span.start.line {
+            span.end.column.saturating_sub(span.start.column)
+        } else {
+            line.chars().count().saturating_sub(span.start.column - 1)
+        };
+
+        format!(
+            "{}: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.kind,
+            self.message,
+            gutter,
+            span.start.line,
+            span.start.column,
+            gutter,
+            number,
+            line,
+            gutter,
+            indent,
+            "^".repeat(width.max(1))
+        )
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Syntax => write!(f, "Syntax error"),
+            ErrorKind::Compile => write!(f, "Compile error"),
+            ErrorKind::Runtime => write!(f, "Runtime error"),
+            ErrorKind::Warning => write!(f, "Warning"),
+            ErrorKind::OutOfFuel => write!(f, "Out of fuel"),
+            ErrorKind::Cancelled => write!(f, "Cancelled"),
+            ErrorKind::MemoryLimit => write!(f, "Out of memory"),
+            ErrorKind::StackOverflow => write!(f, "Stack overflow"),
+        }
+    }
+}
+
+impl fmt::Display for EstaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)?;
+        if let Some(span) = self.span {
+            write!(f, " at {}:{}", span.start.line, span.start.column)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for EstaError {}
+
+/// The VM's values and heap report failures as bare messages, which are
+/// runtime errors unless they are about one of the VM's limits
+impl From<&'static str> for EstaError {
+    fn from(message: &'static str) -> EstaError {
+        let kind = match message {
+            MEMORY_LIMIT => ErrorKind::MemoryLimit,
+            STACK_OVERFLOW => ErrorKind::StackOverflow,
+            _ => ErrorKind::Runtime,
+        };
+        EstaError::new(kind, message)
+    }
+}
+
+/// Stages that report every error they find return a Vec of them, which
+/// a single error converts into
+impl From<EstaError> for Vec<EstaError> {
+    fn from(error: EstaError) -> Vec<EstaError> {
+        vec![error]
+    }
+}
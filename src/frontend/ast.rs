@@ -1,11 +1,38 @@
+use crate::error::Span;
 use std::collections::HashMap;
 
+/// Every statement and expression remembers the span of source it was parsed
+/// from. Nodes that are desugared out of other nodes take on their span.
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
 /// Statement AST
 ///     Block: Scoped Block
 ///     FlatBlock: Unscoped Block
 /// ...
 #[derive(Debug, Clone)]
-pub enum Stmt {
+pub enum StmtKind {
     Block(Vec<Box<Stmt>>, bool),
     If(Box<Expr>, Box<Stmt>, Box<Stmt>),
     While(Box<Expr>, Box<Stmt>),
@@ -17,37 +44,40 @@ pub enum Stmt {
 }
 
 #[derive(Debug, Clone)]
-pub enum Expr {
+pub enum ExprKind {
     Id(Identifier),
     Dot(Identifier, Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
     Literal(Literal),
     List(Vec<Box<Expr>>),
     BinaryOp(Box<Expr>, Opcode, Box<Expr>),
     UnaryOp(Opcode, Box<Expr>),
-    FunCall(String, Vec<Expr>),
+    FunCall(Identifier, Vec<Expr>),
 }
 
 #[derive(Debug, Clone)]
 pub struct Identifier {
     pub id: String,
     pub type_of: String,
+    pub span: Span,
 }
 
 impl Identifier {
-    pub fn new(id: String) -> Self {
+    pub fn new(id: String, span: Span) -> Self {
         Identifier {
             id,
             type_of: "Dynamic".to_string(),
+            span,
         }
     }
-    pub fn new_typed(id: String, type_of: String) -> Self {
-        Identifier { id, type_of }
+    pub fn new_typed(id: String, type_of: String, span: Span) -> Self {
+        Identifier { id, type_of, span }
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Literal {
-    Number(i64),
+    Number(i32),
     Boolean(bool),
     String(String),
     Nil,
@@ -62,8 +92,8 @@ pub struct EstaStruct {
 }
 
 impl EstaStruct {
-    pub fn new(s: Stmt) -> EstaStruct {
-        if let Stmt::Struct(id, fields_list) = s {
+    pub fn new(s: StmtKind) -> EstaStruct {
+        if let StmtKind::Struct(id, fields_list) = s {
             let tag = 0;
             let size = 2 + fields_list.len();
             //            let fields = HashMap::new();
@@ -0,0 +1,307 @@
+use crate::frontend::unescape;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// A lexing error, along with the byte offsets of the source it is about
+pub type LexError = (usize, &'static str, usize);
+
+/// Every token is handed to the parser along with its span, as byte offsets
+pub type Spanned = Result<(usize, Tok, usize), LexError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    // Keywords
+    Var,
+    While,
+    If,
+    Else,
+    For,
+    Fun,
+    Struct,
+    Return,
+    And,
+    Or,
+    Not,
+    True,
+    False,
+    Nil,
+    // Punctuation
+    Semicolon,
+    Comma,
+    Dot,
+    Colon,
+    Arrow,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    // Operators
+    Equal,
+    EqualEqual,
+    BangEqual,
+    Lesser,
+    LesserEqual,
+    Greater,
+    GreaterEqual,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    // Literals
+    Num(i32),
+    Str(String),
+    Ident(String),
+}
+
+/// # The Esta Lexer
+///
+/// Turns source into the tokens the parser is generated for. Whitespace and
+/// comments, both `// line` and `/* block */`, are skipped. Numbers may be
+/// written in decimal, hex (0x) or binary (0b), with underscores anywhere after
+/// their first digit. String escapes are replaced while lexing.
+pub struct Lexer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer {
+            input,
+            chars: input.char_indices().peekable(),
+        }
+    }
+
+    // Byte offset of the next character, or the end of input
+    fn offset(&mut self) -> usize {
+        match self.chars.peek() {
+            Some((idx, _)) => *idx,
+            None => self.input.len(),
+        }
+    }
+
+    // Consumes the next character if it is c
+    fn eat(&mut self, c: char) -> bool {
+        match self.chars.peek() {
+            Some((_, next)) if *next == c => {
+                self.chars.next();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Consumes characters for as long as pred holds
+    fn eat_while<F: Fn(char) -> bool>(&mut self, pred: F) {
+        while let Some((_, c)) = self.chars.peek() {
+            if !pred(*c) {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    // Skips over whitespace and comments, an unterminated block comment is an error
+    fn skip_trivia(&mut self) -> Result<(), LexError> {
+        loop {
+            self.eat_while(char::is_whitespace);
+            let start = self.offset();
+            let rest = &self.input[start..];
+            if rest.starts_with("//") {
+                self.eat_while(|c| c != '\n');
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                match comment.find("*/") {
+                    Some(len) => {
+                        let end = start + 2 + len + 2;
+                        while self.offset() < end {
+                            self.chars.next();
+                        }
+                    }
+                    None => return Err((start, "Unterminated block comment", self.input.len())),
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn number(&mut self, start: usize) -> Result<Tok, LexError> {
+        let rest = &self.input[start..];
+        let radix = if rest.starts_with("0x") {
+            16
+        } else if rest.starts_with("0b") {
+            2
+        } else {
+            10
+        };
+        // The first digit has already been consumed, unless it was the 0 of a prefix
+        let digits_start = if radix != 10 {
+            self.chars.next();
+            self.offset()
+        } else {
+            start
+        };
+        self.eat_while(|c| c.is_alphanumeric() || c == '_');
+        let end = self.offset();
+
+        let digits: String = self.input[digits_start..end]
+            .chars()
+            .filter(|c| *c != '_')
+            .collect();
+        if digits.is_empty() || self.input[digits_start..].starts_with('_') {
+            return Err((start, "Expected digits in number literal", end));
+        }
+        if !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err((start, "Invalid digit in number literal", end));
+        }
+        i32::from_str_radix(&digits, radix)
+            .map(Tok::Num)
+            .map_err(|_| (start, "Number literal is too large", end))
+    }
+
+    fn string(&mut self, start: usize) -> Result<Tok, LexError> {
+        loop {
+            match self.chars.next() {
+                Some((_, '\\')) => {
+                    self.chars.next();
+                }
+                Some((idx, '"')) => {
+                    let end = idx + 1;
+                    return unescape(&self.input[start + 1..idx])
+                        .map(Tok::Str)
+                        .map_err(|error| (start, error, end));
+                }
+                Some(_) => {}
+                None => return Err((start, "Unterminated string", self.input.len())),
+            }
+        }
+    }
+
+    fn word(&mut self, start: usize) -> Tok {
+        self.eat_while(|c| c.is_alphanumeric() || c == '_');
+        let word = &self.input[start..self.offset()];
+        match word {
+            "var" => Tok::Var,
+            "while" => Tok::While,
+            "if" => Tok::If,
+            "else" => Tok::Else,
+            "for" => Tok::For,
+            "fun" => Tok::Fun,
+            "struct" => Tok::Struct,
+            "return" => Tok::Return,
+            "and" => Tok::And,
+            "or" => Tok::Or,
+            "not" => Tok::Not,
+            "True" => Tok::True,
+            "False" => Tok::False,
+            "Nil" => Tok::Nil,
+            _ => Tok::Ident(word.to_string()),
+        }
+    }
+
+    // Picks the two character token if the next character completes it
+    fn either(&mut self, next: char, long: Tok, short: Tok) -> Tok {
+        if self.eat(next) {
+            long
+        } else {
+            short
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Spanned;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.skip_trivia() {
+            // Skip to the end so that the error is only reported once
+            while self.chars.next().is_some() {}
+            return Some(Err(e));
+        }
+        let (start, c) = self.chars.next()?;
+        let tok = match c {
+            ';' => Tok::Semicolon,
+            ',' => Tok::Comma,
+            '.' => Tok::Dot,
+            ':' => Tok::Colon,
+            '(' => Tok::LeftParen,
+            ')' => Tok::RightParen,
+            '{' => Tok::LeftBrace,
+            '}' => Tok::RightBrace,
+            '[' => Tok::LeftBracket,
+            ']' => Tok::RightBracket,
+            '+' => Tok::Plus,
+            '*' => Tok::Star,
+            '/' => Tok::Slash,
+            '%' => Tok::Percent,
+            '-' => self.either('>', Tok::Arrow, Tok::Minus),
+            '=' => self.either('=', Tok::EqualEqual, Tok::Equal),
+            '<' => self.either('=', Tok::LesserEqual, Tok::Lesser),
+            '>' => self.either('=', Tok::GreaterEqual, Tok::Greater),
+            '!' if self.eat('=') => Tok::BangEqual,
+            '"' => match self.string(start) {
+                Ok(tok) => tok,
+                Err(e) => return Some(Err(e)),
+            },
+            c if c.is_ascii_digit() => match self.number(start) {
+                Ok(tok) => tok,
+                Err(e) => return Some(Err(e)),
+            },
+            c if c.is_alphabetic() => self.word(start),
+            c => return Some(Err((start, "Invalid token", start + c.len_utf8()))),
+        };
+        Some(Ok((start, tok, self.offset())))
+    }
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Tok::Var => "var",
+            Tok::While => "while",
+            Tok::If => "if",
+            Tok::Else => "else",
+            Tok::For => "for",
+            Tok::Fun => "fun",
+            Tok::Struct => "struct",
+            Tok::Return => "return",
+            Tok::And => "and",
+            Tok::Or => "or",
+            Tok::Not => "not",
+            Tok::True => "True",
+            Tok::False => "False",
+            Tok::Nil => "Nil",
+            Tok::Semicolon => ";",
+            Tok::Comma => ",",
+            Tok::Dot => ".",
+            Tok::Colon => ":",
+            Tok::Arrow => "->",
+            Tok::LeftParen => "(",
+            Tok::RightParen => ")",
+            Tok::LeftBrace => "{",
+            Tok::RightBrace => "}",
+            Tok::LeftBracket => "[",
+            Tok::RightBracket => "]",
+            Tok::Equal => "=",
+            Tok::EqualEqual => "==",
+            Tok::BangEqual => "!=",
+            Tok::Lesser => "<",
+            Tok::LesserEqual => "<=",
+            Tok::Greater => ">",
+            Tok::GreaterEqual => ">=",
+            Tok::Plus => "+",
+            Tok::Minus => "-",
+            Tok::Star => "*",
+            Tok::Slash => "/",
+            Tok::Percent => "%",
+            Tok::Num(n) => return write!(f, "{}", n),
+            Tok::Str(s) => return write!(f, "{:?}", s),
+            Tok::Ident(id) => return write!(f, "{}", id),
+        };
+        write!(f, "{}", text)
+    }
+}
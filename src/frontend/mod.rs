@@ -1,22 +1,112 @@
 pub mod ast;
+pub mod lexer;
 // pub mod fold;
 // pub mod types;
 
 #[cfg(test)]
 mod tests;
 
-use self::ast::Stmt;
+use self::ast::{Stmt, StmtKind};
+use self::lexer::{LexError, Lexer};
+use crate::error::{EstaError, SourceMap};
+use lalrpop_util::ParseError;
 // use self::types::TypeAssistant;
 // use crate::frontend::types::TypeCollector;
 
-lalrpop_mod!(grammar);
+lalrpop_mod!(
+    #[allow(clippy::all, unused_parens)]
+    grammar
+);
 
-pub fn run(input: &str) -> Result<Stmt, &'static str> {
-    // TODO: Use LALRPOP for error analysis
-    // TODO: Write a custom lexer for comments
-    let stmts = grammar::StmtsParser::new()
-        .parse(input)
-        .map_err(|_| "Parsing Error")?;
-    let stmts = Stmt::Block(stmts, false);
-    Ok(stmts)
+/// Parses the whole input, reporting every syntax error found rather than
+/// stopping at the first one
+pub fn run(input: &str) -> Result<Stmt, Vec<EstaError>> {
+    let map = SourceMap::new(input);
+    let mut recovered = Vec::new();
+    let result = grammar::StmtsParser::new().parse(&map, &mut recovered, Lexer::new(input));
+
+    let mut errors: Vec<EstaError> = recovered
+        .into_iter()
+        .map(|e| parse_error(&map, input, e.error))
+        .collect();
+    match result {
+        Ok(stmts) if errors.is_empty() => {
+            let stmts = Stmt::new(StmtKind::Block(stmts, false), map.span(0, input.len()));
+            Ok(stmts)
+        }
+        Ok(_) => Err(errors),
+        Err(e) => {
+            errors.push(parse_error(&map, input, e));
+            Err(errors)
+        }
+    }
+}
+
+// Turns LALRPOP's byte offsets into a span and its token names into a readable message
+fn parse_error<T: ToString>(
+    map: &SourceMap,
+    input: &str,
+    error: ParseError<usize, T, LexError>,
+) -> EstaError {
+    let (message, start, end) = match error {
+        ParseError::InvalidToken { location } => {
+            let len = input[location..].chars().next().map_or(0, char::len_utf8);
+            ("Invalid token".to_string(), location, location + len)
+        }
+        ParseError::UnrecognizedToken {
+            token: Some((start, token, end)),
+            expected,
+        } => {
+            let message = format!("Unexpected token `{}`", token.to_string());
+            (with_expected(message, &expected), start, end)
+        }
+        ParseError::UnrecognizedToken {
+            token: None,
+            expected,
+        } => {
+            let message = with_expected("Unexpected end of input".to_string(), &expected);
+            (message, input.len(), input.len())
+        }
+        ParseError::ExtraToken {
+            token: (start, token, end),
+        } => {
+            let message = format!("Unexpected token `{}` after the end", token.to_string());
+            (message, start, end)
+        }
+        ParseError::User {
+            error: (start, message, end),
+        } => (message.to_string(), start, end),
+    };
+    EstaError::syntax(message, map.span(start, end))
+}
+
+fn with_expected(message: String, expected: &[String]) -> String {
+    if expected.is_empty() {
+        message
+    } else {
+        format!("{}, expected one of {}", message, expected.join(", "))
+    }
+}
+
+/// Replaces the escape sequences in the body of a string literal with the
+/// characters they stand for
+pub fn unescape(s: &str) -> Result<String, &'static str> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            _ => return Err("Invalid escape sequence"),
+        }
+    }
+    Ok(out)
 }
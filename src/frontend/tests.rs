@@ -4,148 +4,399 @@ use crate::frontend;
 fn test_var() {
     let input = "var a;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     // Err because there is no semicolon
     let input = "var a";
     let result = frontend::run(input);
-    assert_eq!(result.is_err(), true);
+    assert!(result.is_err());
 
     let input = "var a = 1;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a: num;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 }
 
 #[test]
 fn test_op() {
     let input = "var a = 1 + 1;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a = 1 * 1 + 2 / 3 - 4;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a = True or False;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a = True and False;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a = 1 == 2;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a = 1 != 2;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a = 1 < 2;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 }
 
 #[test]
 fn test_decl() {
     let input = "fun foo() {}";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo(a) {}";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo(a: num) {}";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo(a, b) {}";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo(a: num, b: nil) {}";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo() -> nil {}";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo(a) { return a; } ";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "fun foo(a: num, b: nil) -> num { return a * b; }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 }
 
 #[test]
 fn test_control() {
     let input = "for var i = 0; i < 4; i = i + 1; { }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var i; while i != 0 { i = 0; }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "if False { } ";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "if False { } else { }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "if False { } else { if True { } }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 }
 
 #[test]
 fn test_struct() {
     let input = "struct Foo { a }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "struct Foo { a: num }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "struct Foo { a: num, b }";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 }
 
 #[test]
 fn test_list() {
     let input = "var xs = [];";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var xs = [1];";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var xs = [1, 2, 3];";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
+
+    let input = "var x = xs[0];";
+    let result = frontend::run(input);
+    assert!(result.is_ok());
+
+    let input = "xs[i + 1][0] = [[1], 2];";
+    let result = frontend::run(input);
+    assert!(result.is_ok());
+
+    // Err because there is no index
+    let input = "var x = xs[];";
+    let result = frontend::run(input);
+    assert!(result.is_err());
 }
 
 #[test]
 fn test_dot() {
     let input = "var a; var b = a.b;";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
 
     let input = "var a; var b = a.b();";
     let result = frontend::run(input);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_string() {
+    use crate::frontend::ast::*;
+
+    let input = r#"var s = "hello";"#;
+    let result = frontend::run(input);
+    assert!(result.is_ok());
+
+    let input = r#"var s = "";"#;
+    let result = frontend::run(input);
+    assert!(result.is_ok());
+
+    // Err because the escape sequence is unknown
+    let input = r#"var s = "\q";"#;
+    let result = frontend::run(input);
+    assert!(result.is_err());
+
+    // Err because the string is never closed
+    let input = r#"var s = "hello;"#;
+    let result = frontend::run(input);
+    assert!(result.is_err());
+
+    let input = r#"s = "say \"hi\"\n";"#;
+    let result = frontend::run(input).unwrap();
+    if let StmtKind::Block(stmts, _) = result.kind {
+        if let StmtKind::Assignment(_, rhs) = &stmts[0].kind {
+            if let ExprKind::Literal(Literal::String(s)) = &rhs.kind {
+                assert_eq!(s, "say \"hi\"\n");
+                return;
+            }
+        }
+    }
+    panic!("Expected a string literal assignment");
+}
+
+#[test]
+fn test_errors() {
+    use crate::error::{ErrorKind, Position};
+
+    let error = frontend::run("var a = 1;\nvar b = );")
+        .unwrap_err()
+        .remove(0);
+    assert_eq!(error.kind, ErrorKind::Syntax);
+    assert!(error
+        .message
+        .starts_with("Unexpected token `)`, expected one of"));
+    let span = error.span.unwrap();
+    assert_eq!(span.start, Position { line: 2, column: 9 });
+    assert_eq!(
+        span.end,
+        Position {
+            line: 2,
+            column: 10
+        }
+    );
+
+    let error = frontend::run("var a = 1").unwrap_err().remove(0);
+    assert!(error.message.starts_with("Unexpected end of input"));
+    assert_eq!(
+        error.span.unwrap().start,
+        Position {
+            line: 1,
+            column: 10
+        }
+    );
+
+    let error = frontend::run("var a = 1 # 2;").unwrap_err().remove(0);
+    assert_eq!(error.message, "Invalid token");
+    assert_eq!(
+        error.span.unwrap().start,
+        Position {
+            line: 1,
+            column: 11
+        }
+    );
+
+    let error = frontend::run("var a =\n    \"b\\q\";")
+        .unwrap_err()
+        .remove(0);
+    assert_eq!(error.message, "Invalid escape sequence");
+    let span = error.span.unwrap();
+    assert_eq!(span.start, Position { line: 2, column: 5 });
+    assert_eq!(
+        span.end,
+        Position {
+            line: 2,
+            column: 10
+        }
+    );
+    assert_eq!(
+        error.render("var a =\n    \"b\\q\";"),
+        "Syntax error: Invalid escape sequence\n --> 2:5\n  |\n2 |     \"b\\q\";\n  |     ^^^^^"
+    );
+}
+
+#[test]
+fn test_spans() {
+    use crate::error::{Position, Span};
+    use crate::frontend::ast::*;
+
+    let span = |line, column, end_line, end_column| Span {
+        start: Position { line, column },
+        end: Position {
+            line: end_line,
+            column: end_column,
+        },
+    };
+
+    let input = "var a = 1;\nvar b = (a + 2) * 3;";
+    let result = frontend::run(input).unwrap();
+    assert_eq!(result.span, span(1, 1, 2, 21));
+    let stmts = match result.kind {
+        StmtKind::Block(stmts, _) => stmts,
+        _ => panic!("Expected a block"),
+    };
+    assert_eq!(stmts[1].span, span(2, 1, 2, 20));
+    let rhs = match &stmts[1].kind {
+        StmtKind::Block(decl, false) => match &decl[1].kind {
+            StmtKind::Assignment(lhs, rhs) => {
+                assert_eq!(lhs.span, span(2, 5, 2, 6));
+                rhs
+            }
+            _ => panic!("Expected an assignment"),
+        },
+        _ => panic!("Expected a declaration"),
+    };
+    assert_eq!(rhs.span, span(2, 10, 2, 20));
+    match &rhs.kind {
+        ExprKind::BinaryOp(lhs, Opcode::Mul, _) => assert_eq!(lhs.span, span(2, 10, 2, 15)),
+        _ => panic!("Expected a multiplication"),
+    }
+
+    // The while loop a for loop desugars into keeps the for loop's span, and the
+    // increment moved to the end of its body keeps its own
+    let input = "for var i = 0; i < 3; i = i + 1; {\n    f(i);\n}";
+    let result = frontend::run(input).unwrap();
+    let stmts = match result.kind {
+        StmtKind::Block(stmts, _) => stmts,
+        _ => panic!("Expected a block"),
+    };
+    assert_eq!(stmts[0].span, span(1, 1, 3, 2));
+    let (test, body) = match &stmts[0].kind {
+        StmtKind::Block(block, true) => match &block[1].kind {
+            StmtKind::While(test, body) => {
+                assert_eq!(block[1].span, span(1, 1, 3, 2));
+                (test, body)
+            }
+            _ => panic!("Expected a while loop"),
+        },
+        _ => panic!("Expected a scoped block"),
+    };
+    assert_eq!(test.span, span(1, 16, 1, 21));
+    assert_eq!(body.span, span(1, 34, 3, 2));
+    match &body.kind {
+        StmtKind::Block(body, true) => {
+            assert_eq!(body[0].span, span(2, 5, 2, 9));
+            assert_eq!(body[1].span, span(1, 23, 1, 32));
+        }
+        _ => panic!("Expected a scoped block"),
+    }
+}
+
+#[test]
+fn test_comments() {
+    let input = "
+        // A line comment
+        var a = 1; // Trailing comment
+        /* A block comment
+           spanning lines, with a // inside */
+        var b = a /* inline */ + 2;
+        // A comment at the end of input";
+    let result = frontend::run(input);
+    assert!(result.is_ok());
+
+    // Err because the block comment is never closed
+    let input = "var a = 1; /* var b;";
+    let result = frontend::run(input);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_tokens() {
+    use crate::frontend::lexer::*;
+
+    let lex = |input| Lexer::new(input).collect::<Vec<Spanned>>();
+
+    let tokens = lex("0x1F 0b101 1_000_000 42");
+    let numbers = vec![31, 5, 1_000_000, 42];
+    let spans = vec![(0, 4), (5, 10), (11, 20), (21, 23)];
+    for ((tok, n), (start, end)) in tokens.into_iter().zip(numbers).zip(spans) {
+        assert_eq!(tok, Ok((start, Tok::Num(n), end)));
+    }
+
+    let tokens = lex("x->y<=\"a\\\"b\"");
+    let expected = vec![
+        Ok((0, Tok::Ident("x".to_string()), 1)),
+        Ok((1, Tok::Arrow, 3)),
+        Ok((3, Tok::Ident("y".to_string()), 4)),
+        Ok((4, Tok::LesserEqual, 6)),
+        Ok((6, Tok::Str("a\"b".to_string()), 12)),
+    ];
+    assert_eq!(tokens, expected);
+
+    assert!(lex("0x").iter().any(|t| t.is_err()));
+    assert!(lex("0b102").iter().any(|t| t.is_err()));
+    assert!(lex("12abc").iter().any(|t| t.is_err()));
+    assert!(lex("99999999999999999999").iter().any(|t| t.is_err()));
+    assert_eq!(lex("2147483647")[0], Ok((0, Tok::Num(i32::MAX), 10)));
+    let too_large = Err((0, "Number literal is too large", 10));
+    assert_eq!(lex("0xFFFFFFFF")[0], too_large);
+    assert_eq!(lex("3000000000")[0], too_large);
+    assert_eq!(lex("\"abc"), vec![Err((0, "Unterminated string", 4))]);
+    assert_eq!(lex("a # b")[1], Err((2, "Invalid token", 3)));
+}
+
+#[test]
+fn test_recovery() {
+    // Each broken statement is skipped up to its ";", or up to the "}" of the
+    // block it is in, and parsing carries on from there
+    let input = "
+        var a = ;
+        fun f() {
+            var b = 1 +
+        }
+        var c = 1;
+        while c < 3 { c = c + ; }
+        c = ) 2;";
+    let errors = frontend::run(input).unwrap_err();
+    let lines: Vec<usize> = errors.iter().map(|e| e.span.unwrap().start.line).collect();
+    assert_eq!(lines, vec![2, 5, 7, 8]);
+
+    // Errors that can't be recovered from are still reported after the others
+    let input = "var a = ; fun f() {";
+    let errors = frontend::run(input).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[1].message.starts_with("Unexpected end of input"));
 }
@@ -1,24 +1,23 @@
 /// This managed runtime is a wrapper around running ESTA code and will automatically
 /// halt the runtime of the VM if the number of steps has exceeded the limit.
 fn managed_runtime(program: &str, max_steps: usize) -> bool {
-    use crate::vm::*;
     use crate::*;
 
-    let stmts = frontend::run(&program).unwrap();
+    let stmts = frontend::run(program).unwrap();
     let (stmts, md) = middleend::run(stmts).unwrap();
     let prog = backend::generate(stmts, md).unwrap();
     let mut vm = vm::VirtualMachine::new(prog);
-    let mut count = 0;
-    while let Ok(VMStatus::RUNNING) = vm.step() {
-        count += 1;
-        if count > max_steps {
-            return false;
+    vm.set_fuel(Some(max_steps));
+    match vm.run() {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Test finished with error: {}", e);
+            false
         }
     }
-    true
 }
 
-fn do_tests(paths: &Vec<&str>, limit: usize) {
+fn do_tests(paths: &[&str], limit: usize) {
     use std::fs;
 
     let res = paths
@@ -28,31 +27,31 @@ fn do_tests(paths: &Vec<&str>, limit: usize) {
         .zip(paths.iter())
         .inspect(|(r, p)| println!("Test {}: {}", p, r))
         .map(|(r, _)| r)
-        .fold(true, |acc, res| acc && res);
-    assert_eq!(res, true);
+        .collect::<Vec<bool>>();
+    assert!(res.iter().all(|r| *r));
 }
 
-//#[test]
-//fn test_function_calls() {
-//    let paths = vec![
-//        "testsuite/simple_function1.est",
-//        "testsuite/simple_function2.est",
-//        "testsuite/simple_function3.est",
-//        "testsuite/simple_function4.est",
-//        "testsuite/simple_function5.est",
-//    ];
-//    do_tests(&paths, 2000);
-//}
-//
-//#[test]
-//fn test_control_flow() {
-//    let paths = vec![
-//        "testsuite/while.est",
-//        "testsuite/for.est",
-//        "testsuite/if.est",
-//    ];
-//    do_tests(&paths, 20000);
-//}
+#[test]
+fn test_function_calls() {
+    let paths = vec![
+        "testsuite/simple_function1.est",
+        "testsuite/simple_function2.est",
+        "testsuite/simple_function3.est",
+        "testsuite/simple_function4.est",
+        "testsuite/simple_function5.est",
+    ];
+    do_tests(&paths, 2000);
+}
+
+#[test]
+fn test_control_flow() {
+    let paths = vec![
+        "testsuite/while.est",
+        "testsuite/for.est",
+        "testsuite/if.est",
+    ];
+    do_tests(&paths, 20000);
+}
 
 #[test]
 fn test_assignments() {
@@ -60,14 +59,197 @@ fn test_assignments() {
     do_tests(&paths, 2000);
 }
 
-//#[test]
-//fn test_struct() {
-//    let paths = vec!["testsuite/struct.est"];
-//    do_tests(&paths, 2000);
-//}
-//
-//#[test]
-//fn test_real_world() {
-//    let paths = vec!["testsuite/realworld.est"];
-//    do_tests(&paths, 20000);
-//}
+#[test]
+fn test_struct() {
+    let paths = vec!["testsuite/struct.est"];
+    do_tests(&paths, 2000);
+}
+
+#[test]
+fn test_real_world() {
+    let paths = vec!["testsuite/realworld.est"];
+    do_tests(&paths, 20000);
+}
+
+#[test]
+fn test_list() {
+    let paths = vec!["testsuite/list.est"];
+    do_tests(&paths, 2000);
+}
+
+#[test]
+fn test_run() {
+    use crate::error::ErrorKind;
+    use crate::vm::EstaData;
+    use std::fs;
+
+    let buffer = fs::read_to_string("examples/fibonacci.est").expect("Couldn't read file!");
+    let buffer = buffer.replace("fibonacci(30)", "fibonacci(10)");
+    assert_eq!(crate::run(&buffer), Ok(EstaData::new_int(55)));
+
+    assert_eq!(crate::run("var a = 1;"), Ok(EstaData::default()));
+    let errors = crate::run("fun main() { return 1 / 0; }").unwrap_err();
+    assert_eq!(errors[0].kind, ErrorKind::Runtime);
+    assert_eq!(errors[0].message, "Division by zero");
+}
+
+#[test]
+fn test_engine() {
+    use crate::engine::Engine;
+    use crate::error::ErrorKind;
+    use crate::vm::EstaData;
+    use std::convert::TryInto;
+
+    let program = "
+        var limit;
+        var calls = 0;
+        fun add(a, b) { calls = calls + 1; return a + b; }
+        fun below(n) { return n < limit; }
+        fun greet(name: str) -> str { return \"Hello \" + name; }
+        fun main() { return add(limit, 1); }";
+    let mut engine = Engine::new(program).unwrap();
+    engine.set_global("limit", 10).unwrap();
+
+    let sum: i32 = engine
+        .call("add", vec![2.into(), 3.into()])
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert_eq!(sum, 5);
+    assert_eq!(engine.call("below", vec![4.into()]), Ok(true.into()));
+    let greeting: String = engine
+        .call("greet", vec!["Esta".into()])
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert_eq!(greeting, "Hello Esta");
+    assert_eq!(engine.run(), Ok(EstaData::new_int(11)));
+    assert_eq!(engine.global("calls"), Some(EstaData::new_int(2)));
+
+    // Failed calls are reported and leave the engine usable
+    let error = engine.call("add", vec![1.into(), true.into()]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Runtime);
+    let error = engine.call("missing", Vec::new()).unwrap_err();
+    assert_eq!(error.message, "Unknown function missing");
+    let error = engine.call("add", vec![1.into()]).unwrap_err();
+    assert_eq!(error.message, "add takes 2 arguments but 1 was given");
+    let error = engine.set_global("missing", ()).unwrap_err();
+    assert_eq!(error.message, "Unknown global missing");
+    let result: Result<i32, _> = engine.call("below", vec![1.into()]).unwrap().try_into();
+    assert_eq!(result.unwrap_err().message, "Expected Num but found Bool");
+    assert_eq!(engine.call("add", vec![1.into(), 1.into()]), Ok(2.into()));
+
+    // Top level statements that fail are unwound, and run again by the next call
+    let mut engine = Engine::new("var a = 1 / 0; fun f() { return 3; }").unwrap();
+    for _ in 0..2 {
+        let error = engine.call("f", Vec::new()).unwrap_err();
+        assert_eq!(error.message, "Division by zero");
+    }
+    assert_eq!(engine.run().unwrap_err().message, "Division by zero");
+
+    let mut engine = Engine::new("var d; var a = 6 / d; fun f() { return a; }").unwrap();
+    engine.set_global("d", 0).unwrap();
+    assert!(engine.call("f", Vec::new()).is_err());
+    engine.set_global("d", 2).unwrap();
+    assert_eq!(engine.call("f", Vec::new()), Ok(3.into()));
+}
+
+#[test]
+fn test_host_objects() {
+    use crate::engine::Engine;
+    use crate::error::EstaError;
+    use crate::middleend::Settings;
+    use crate::vm::heap::Heap;
+    use crate::vm::object::HostObject;
+    use crate::vm::EstaData;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::rc::Rc;
+
+    struct Store {
+        rows: HashMap<String, i32>,
+        dropped: Rc<Cell<usize>>,
+    }
+
+    impl HostObject for Store {
+        fn type_name(&self) -> &str {
+            "Store"
+        }
+
+        fn call(
+            &mut self,
+            method: &str,
+            _heap: &mut Heap,
+            args: Vec<EstaData>,
+        ) -> Result<EstaData, EstaError> {
+            let key: String = args[0].clone().try_into()?;
+            match method {
+                "put" => {
+                    let value = args[1].clone().try_into()?;
+                    self.rows.insert(key, value);
+                    Ok(().into())
+                }
+                "get" => Ok(self.rows.get(&key).cloned().unwrap_or(0).into()),
+                _ => Err(EstaError::runtime(format!(
+                    "Store has no method {}",
+                    method
+                ))),
+            }
+        }
+    }
+
+    impl Drop for Store {
+        fn drop(&mut self) {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+
+    let program = "
+        var db;
+        fun count(key) {
+            db.put(key, db.get(key) + 1);
+            return db.get(key);
+        }
+        fun scratch() {
+            var temp = open();
+            temp.put(\"a\", 1);
+            return temp.get(\"a\");
+        }
+        fun misuse(x) { return x.get(\"a\"); }
+        fun unknown() { return db.drop(\"a\"); }";
+    let mut settings: Settings = Default::default();
+    settings.natives.insert("open".to_string(), Some(0));
+    let mut engine = Engine::with_settings(program, &settings).unwrap();
+
+    let dropped = Rc::new(Cell::new(0));
+    let opened = dropped.clone();
+    engine.register_native("open", Some(0), move |_heap, _args| {
+        Ok(EstaData::new_object(Store {
+            rows: HashMap::new(),
+            dropped: opened.clone(),
+        }))
+    });
+    let db = EstaData::new_object(Store {
+        rows: HashMap::new(),
+        dropped: dropped.clone(),
+    });
+    assert_eq!(db.to_string(), "<Store>");
+    engine.set_global("db", db).unwrap();
+
+    assert_eq!(engine.call("count", vec!["x".into()]), Ok(1.into()));
+    assert_eq!(engine.call("count", vec!["x".into()]), Ok(2.into()));
+    assert_eq!(engine.call("count", vec!["y".into()]), Ok(1.into()));
+
+    // An object is dropped once the last script variable holding it goes away
+    assert_eq!(engine.call("scratch", Vec::new()), Ok(1.into()));
+    assert_eq!(dropped.get(), 1);
+
+    let error = engine.call("misuse", vec![1.into()]).unwrap_err();
+    assert_eq!(error.message, "Only host objects have methods");
+    let error = engine.call("unknown", Vec::new()).unwrap_err();
+    assert_eq!(error.message, "Store has no method drop");
+
+    engine.set_global("db", ()).unwrap();
+    assert_eq!(dropped.get(), 2);
+}
@@ -1,4 +1,6 @@
 pub mod backend;
+pub mod engine;
+pub mod error;
 pub mod frontend;
 pub mod middleend;
 pub mod util;
@@ -17,15 +19,12 @@ extern crate strum_macros;
 extern crate log;
 extern crate env_logger;
 
-pub fn run(input: &str) -> Result<(), &'static str> {
-    let stmts = frontend::run(input)?;
-    let (stmts, md) = middleend::run(stmts)?;
-    let program = backend::generate(stmts, md)?;
-    //    for (j, i) in inst.iter().enumerate() {
-    //        debug!("{: >3} {}", j, i);
-    //    }
-    //    debug!("Data Segment: {:?}", data_segment);
-    //    let mut vm = vm::VirtualMachine::new(inst, data_segment);
-    //    vm.run()?;
-    Ok(())
+use crate::engine::Engine;
+use crate::error::EstaError;
+use crate::vm::EstaData;
+
+/// Compiles and runs a program, returning the value main returned or Nil if
+/// there is no main
+pub fn run(input: &str) -> Result<EstaData, Vec<EstaError>> {
+    Ok(Engine::new(input)?.run()?)
 }
@@ -1,3 +1,4 @@
+use esta::vm::EstaData;
 use std::env;
 use std::fs;
 use std::io;
@@ -34,8 +35,12 @@ fn run_prompt() {
         buffer = format!("fun main() {{ {} }}", buffer);
 
         match esta::run(&buffer) {
-            Ok(()) => {}
-            Err(why) => eprintln!("{}", why),
+            Ok(result) => print_result(&result),
+            Err(errors) => {
+                for why in errors {
+                    eprintln!("{}", why.render(&buffer));
+                }
+            }
         };
 
         io::stdout().flush().unwrap();
@@ -45,11 +50,23 @@ fn run_prompt() {
 fn run_file(path: &str) {
     let buffer = fs::read_to_string(path).expect("Couldn't read file!");
     let result = match esta::run(&buffer) {
-        Ok(()) => 0,
-        Err(why) => {
-            eprintln!("{}", why);
+        Ok(result) => {
+            print_result(&result);
+            0
+        }
+        Err(errors) => {
+            for why in errors {
+                eprintln!("{}", why.render(&buffer));
+            }
             1
         }
     };
     process::exit(result);
 }
+
+// Programs that don't return anything have nothing to show
+fn print_result(result: &EstaData) {
+    if *result != EstaData::default() {
+        println!("{}", result);
+    }
+}
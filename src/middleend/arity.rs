@@ -0,0 +1,111 @@
+use crate::error::{EstaError, Span};
+use crate::frontend::ast::*;
+use crate::util::fold::*;
+use std::collections::HashMap;
+
+/// The number of arguments every callable name takes, None if it takes any number
+pub type Signatures = HashMap<String, Option<usize>>;
+
+/// Traverses the AST searching for function declarations, including nested ones,
+/// and struct declarations, whose constructors take no arguments
+pub struct FunctionCollector;
+
+impl Fold for FunctionCollector {
+    type UpT = Vec<(Identifier, Option<usize>)>;
+    type DownT = ();
+
+    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+        Some(children.into_iter().flatten().flatten().collect())
+    }
+
+    // A struct's hook isn't handed its span, so it is found by its statement
+    fn fold_stmt(down: &Self::DownT, s: &Stmt) -> Option<Self::UpT> {
+        match &s.kind {
+            StmtKind::Struct(id, _) => Some(vec![(Identifier::new(id.clone(), s.span), Some(0))]),
+            _ => Self::walk_stmt(down, s),
+        }
+    }
+
+    fn fold_fundecl(
+        down: &Self::DownT,
+        id: &Identifier,
+        params: &[Identifier],
+        body: &Stmt,
+    ) -> Option<Self::UpT> {
+        let signature = Some(vec![(id.clone(), Some(params.len()))]);
+        Self::reduce(vec![signature, Self::fold_stmt(down, body)])
+    }
+}
+
+/// # Arity Checking
+///
+/// Checks that every call is to a function that exists and passes it as many
+/// arguments as it takes. A call may be to a declared function, a struct
+/// constructor, which takes no arguments, or a native. Like the backend's
+/// linking, declared functions take precedence over natives of the same name,
+/// but no two functions or structs may share a name.
+pub struct ArityChecker;
+
+impl ArityChecker {
+    pub fn check(body: &Stmt, natives: &Signatures) -> Vec<EstaError> {
+        let mut signatures = natives.clone();
+        let mut declared: HashMap<String, Span> = HashMap::new();
+        let mut errors = Vec::new();
+        for (id, arity) in FunctionCollector::fold_stmt(&(), body).unwrap_or_default() {
+            if let Some(first) = declared.get(&id.id) {
+                let message = format!(
+                    "{} is already declared at {}:{}",
+                    id.id, first.start.line, first.start.column
+                );
+                errors.push(EstaError::compile(message).with_span(id.span));
+                continue;
+            }
+            declared.insert(id.id.clone(), id.span);
+            signatures.insert(id.id, arity);
+        }
+        errors.extend(ArityChecker::fold_stmt(&signatures, body).unwrap_or_default());
+        errors
+    }
+
+    fn check_call(signatures: &Signatures, id: &Identifier, argc: usize) -> Option<EstaError> {
+        let message = match signatures.get(&id.id) {
+            None => format!("{} is not a function", id.id),
+            Some(Some(arity)) if *arity != argc => format!(
+                "{} takes {} argument{} but {} {} given",
+                id.id,
+                arity,
+                if *arity == 1 { "" } else { "s" },
+                argc,
+                if argc == 1 { "was" } else { "were" }
+            ),
+            Some(_) => return None,
+        };
+        Some(EstaError::compile(message).with_span(id.span))
+    }
+}
+
+impl Fold for ArityChecker {
+    type UpT = Vec<EstaError>;
+    type DownT = Signatures;
+
+    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+        Some(children.into_iter().flatten().flatten().collect())
+    }
+
+    fn fold_funcall(down: &Self::DownT, id: &Identifier, args: &[Expr]) -> Option<Self::UpT> {
+        let mut children: Vec<Option<Self::UpT>> =
+            args.iter().map(|e| Self::fold_expr(down, e)).collect();
+        children.push(ArityChecker::check_call(down, id, args.len()).map(|e| vec![e]));
+        Self::reduce(children)
+    }
+
+    // Methods are looked up on the struct at runtime, so only their arguments are checked
+    fn fold_dot(down: &Self::DownT, _this: &Identifier, action: &Expr) -> Option<Self::UpT> {
+        match &action.kind {
+            ExprKind::FunCall(_, args) => {
+                Self::reduce(args.iter().map(|e| Self::fold_expr(down, e)).collect())
+            }
+            _ => Self::fold_expr(down, action),
+        }
+    }
+}
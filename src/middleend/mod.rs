@@ -1,26 +1,86 @@
+pub mod arity;
+pub mod optimize;
+pub mod resolver;
+#[cfg(test)]
+mod tests;
+pub mod typecheck;
 mod types;
 
+use crate::error::{ErrorKind, EstaError, Span};
 use crate::frontend::ast::*;
+use crate::middleend::arity::*;
+use crate::middleend::optimize::*;
+use crate::middleend::resolver::*;
+use crate::middleend::typecheck::*;
 use crate::middleend::types::*;
+use crate::vm::native::Natives;
+use std::collections::HashMap;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct MetaData {
     pub structs: Vec<EstaStruct>,
+    pub slots: HashMap<Span, Slot>, // Where every variable use lives, by the use's span
+    pub guards: HashMap<Span, Type>, // Values to check at runtime, with the type they must have
+    pub warnings: Vec<EstaError>,
 }
 
 impl MetaData {
     pub fn new() -> MetaData {
-        let structs = Vec::new();
-        MetaData { structs }
+        Default::default()
+    }
+}
+
+/// Settings
+///
+/// Everything about how a program is checked that is up to whoever compiles it
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub natives: Signatures, // Every native the program may call, the builtins by default
+    pub strictness: Strictness,
+    pub optimize: bool, // Whether to simplify the program once it has been checked
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            natives: Natives::new().signatures(),
+            strictness: Default::default(),
+            optimize: true,
+        }
     }
 }
 
-pub fn run(stmts: Stmt) -> Result<(Stmt, MetaData), &'static str> {
-    let structs = TypeCollector::collect_types(&stmts).ok_or("No structs found")?;
+/// Reports every error found in the program, or hands the program on along with
+/// what the backend needs to know about it and any warnings
+pub fn run(stmts: Stmt) -> Result<(Stmt, MetaData), Vec<EstaError>> {
+    run_with(stmts, &Default::default())
+}
+
+pub fn run_with(stmts: Stmt, settings: &Settings) -> Result<(Stmt, MetaData), Vec<EstaError>> {
+    let structs = TypeCollector::collect_types(&stmts)
+        .ok_or_else(|| EstaError::compile("Couldn't collect struct definitions"))?;
+    let resolution = Resolver::resolve(&stmts);
+    let arity_errors = ArityChecker::check(&stmts, &settings.natives);
+    let (type_errors, guards) = TypeChecker::check(&stmts, settings.strictness);
+    let (warnings, errors): (Vec<EstaError>, Vec<EstaError>) = resolution
+        .diagnostics
+        .into_iter()
+        .chain(arity_errors)
+        .chain(type_errors)
+        .partition(|d| d.kind == ErrorKind::Warning);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let stmts = if settings.optimize {
+        Optimizer::optimize(stmts)
+    } else {
+        stmts
+    };
     let mut md = MetaData::new();
     md.structs = structs;
+    md.slots = resolution.slots;
+    md.guards = guards;
+    md.warnings = warnings;
     Ok((stmts, md))
 }
-
-// TODO: Discover all variables in a given scope
-// TODO: Discover and check function arity
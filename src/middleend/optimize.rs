@@ -0,0 +1,193 @@
+use crate::error::Span;
+use crate::frontend::ast::*;
+use crate::util::rewrite::*;
+use crate::vm::{EstaData, EstaKind};
+
+/// # Optimizer
+///
+/// Simplifies the program once it has been checked. Operators applied to literals
+/// are evaluated ahead of time, branches on a literal test are replaced by the one
+/// that would be taken and statements that can never be reached are removed.
+///
+/// Literals are evaluated by the VM's own operators, so folding can't change what
+/// a program does. Anything that would be a runtime error is left for the VM to
+/// report. Rewritten nodes keep the span of the node they replace.
+pub struct Optimizer;
+
+impl Optimizer {
+    pub fn optimize(body: Stmt) -> Stmt {
+        Optimizer::rewrite_stmt(&(), body)
+    }
+
+    fn to_data(lit: &Literal) -> EstaData {
+        match lit {
+            Literal::Number(n) => EstaData::new_int(*n),
+            Literal::Boolean(b) => EstaData::new_bool(*b),
+            Literal::String(s) => EstaData::new_str(s.clone()),
+            Literal::Nil => Default::default(),
+        }
+    }
+
+    fn to_literal(data: Result<EstaData, &'static str>) -> Option<Literal> {
+        let data = data.ok()?;
+        let lit = match data.kind() {
+            EstaKind::Num => Literal::Number(data.eval_int().ok()?),
+            EstaKind::Bool => Literal::Boolean(data.eval_bool().ok()?),
+            EstaKind::Str => Literal::String(data.eval_str().ok()?),
+            EstaKind::Nil => Literal::Nil,
+            _ => return None,
+        };
+        Some(lit)
+    }
+
+    fn literal(e: &Expr) -> Option<&Literal> {
+        match &e.kind {
+            ExprKind::Literal(lit) => Some(lit),
+            _ => None,
+        }
+    }
+
+    fn fold_binary(lhs: &Expr, op: &Opcode, rhs: &Expr) -> Option<Literal> {
+        let lhs = Optimizer::to_data(Optimizer::literal(lhs)?);
+        let rhs = Optimizer::to_data(Optimizer::literal(rhs)?);
+        let op = match op {
+            Opcode::Add => EstaData::new_add,
+            Opcode::Sub => EstaData::new_sub,
+            Opcode::Mul => EstaData::new_mul,
+            Opcode::Div => EstaData::new_div,
+            Opcode::Mod => EstaData::new_mod,
+            Opcode::Greater => EstaData::new_gt,
+            Opcode::GreaterEqual => EstaData::new_ge,
+            Opcode::Lesser => EstaData::new_lt,
+            Opcode::LesserEqual => EstaData::new_le,
+            Opcode::EqualEqual => EstaData::new_eq,
+            Opcode::BangEqual => EstaData::new_ne,
+            _ => return None,
+        };
+        Optimizer::to_literal(op(lhs, rhs))
+    }
+
+    /// Whether control can never fall through past this statement
+    fn returns(s: &Stmt) -> bool {
+        match &s.kind {
+            StmtKind::Return(_) => true,
+            StmtKind::Block(body, _) => body.iter().any(|s| Optimizer::returns(s)),
+            StmtKind::If(_, body, alter) => Optimizer::returns(body) && Optimizer::returns(alter),
+            _ => false,
+        }
+    }
+
+    /// Code that is removed may still declare things the rest of the program
+    /// relies on. Functions and structs are known everywhere so they are always
+    /// kept, while variables are only kept if they belong to the frame the code
+    /// was removed from, since the resolver has already numbered them.
+    fn salvage(s: Stmt, in_frame: bool) -> Vec<Stmt> {
+        match s.kind {
+            StmtKind::Declaration(_) if in_frame => vec![s],
+            StmtKind::FunDecl(..) | StmtKind::Struct(..) => vec![s],
+            StmtKind::Block(body, is_scope) => body
+                .into_iter()
+                .flat_map(|s| Optimizer::salvage(*s, in_frame && !is_scope))
+                .collect(),
+            StmtKind::If(_, body, alter) => vec![*body, *alter]
+                .into_iter()
+                .flat_map(|s| Optimizer::salvage(s, false))
+                .collect(),
+            StmtKind::While(_, body) => Optimizer::salvage(*body, false),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replaces a statement that is never run by whatever has to be kept of it
+    fn eliminate(kept: Stmt, removed: Stmt, span: Span) -> Stmt {
+        let salvaged = Optimizer::salvage(removed, false);
+        if salvaged.is_empty() {
+            return kept;
+        }
+        let mut body = vec![Box::new(kept)];
+        body.extend(salvaged.into_iter().map(Box::new));
+        Stmt::new(StmtKind::Block(body, false), span)
+    }
+}
+
+impl Rewrite for Optimizer {
+    type DownT = ();
+
+    fn rewrite_block(down: &Self::DownT, body: Vec<Box<Stmt>>, is_scope: bool, span: Span) -> Stmt {
+        let mut kept = Vec::new();
+        let mut body = body.into_iter();
+        for s in body.by_ref() {
+            let s = Self::rewrite_stmt(down, *s);
+            let returns = Optimizer::returns(&s);
+            kept.push(Box::new(s));
+            if returns {
+                break;
+            }
+        }
+        kept.extend(
+            body.flat_map(|s| Optimizer::salvage(*s, true))
+                .map(Box::new),
+        );
+        Stmt::new(StmtKind::Block(kept, is_scope), span)
+    }
+
+    fn rewrite_if(down: &Self::DownT, test: Expr, body: Stmt, alter: Stmt, span: Span) -> Stmt {
+        let test = Self::rewrite_expr(down, test);
+        let body = Self::rewrite_stmt(down, body);
+        let alter = Self::rewrite_stmt(down, alter);
+        match Optimizer::literal(&test) {
+            Some(Literal::Boolean(true)) => Optimizer::eliminate(body, alter, span),
+            Some(Literal::Boolean(false)) => Optimizer::eliminate(alter, body, span),
+            _ => Stmt::new(
+                StmtKind::If(Box::new(test), Box::new(body), Box::new(alter)),
+                span,
+            ),
+        }
+    }
+
+    fn rewrite_while(down: &Self::DownT, test: Expr, body: Stmt, span: Span) -> Stmt {
+        let test = Self::rewrite_expr(down, test);
+        let body = Self::rewrite_stmt(down, body);
+        match Optimizer::literal(&test) {
+            Some(Literal::Boolean(false)) => {
+                let empty = Stmt::new(StmtKind::Block(Vec::new(), false), span);
+                Optimizer::eliminate(empty, body, span)
+            }
+            _ => Stmt::new(StmtKind::While(Box::new(test), Box::new(body)), span),
+        }
+    }
+
+    // Logical operators short circuit, so a literal on the left decides the
+    // result on its own, or leaves it up to the right
+    fn rewrite_binary(down: &Self::DownT, lhs: Expr, op: Opcode, rhs: Expr, span: Span) -> Expr {
+        let lhs = Self::rewrite_expr(down, lhs);
+        let rhs = Self::rewrite_expr(down, rhs);
+        match (&op, Optimizer::literal(&lhs)) {
+            (Opcode::And, Some(Literal::Boolean(true)))
+            | (Opcode::Or, Some(Literal::Boolean(false))) => return Expr { span, ..rhs },
+            (Opcode::And, Some(Literal::Boolean(false)))
+            | (Opcode::Or, Some(Literal::Boolean(true))) => return Expr { span, ..lhs },
+            _ => {}
+        }
+        match Optimizer::fold_binary(&lhs, &op, &rhs) {
+            Some(lit) => Expr::new(ExprKind::Literal(lit), span),
+            None => Expr::new(ExprKind::BinaryOp(Box::new(lhs), op, Box::new(rhs)), span),
+        }
+    }
+
+    fn rewrite_unary(down: &Self::DownT, op: Opcode, rhs: Expr, span: Span) -> Expr {
+        let rhs = Self::rewrite_expr(down, rhs);
+        let folded = Optimizer::literal(&rhs).and_then(|lit| {
+            let data = Optimizer::to_data(lit);
+            match op {
+                Opcode::Not => Optimizer::to_literal(EstaData::new_not(data)),
+                Opcode::Sub => Optimizer::to_literal(EstaData::new_neg(data)),
+                _ => None,
+            }
+        });
+        match folded {
+            Some(lit) => Expr::new(ExprKind::Literal(lit), span),
+            None => Expr::new(ExprKind::UnaryOp(op, Box::new(rhs)), span),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::error::{ErrorKind, EstaError, Span};
+use crate::frontend::ast::*;
+use crate::util::fold::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Where a variable lives at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Local { depth: usize, index: usize }, // Environment frames up from the top, offset in that frame
+    Global(usize),                        // Offset in the global environment frame
+}
+
+/// Everything the resolver found out about a program
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    pub slots: HashMap<Span, Slot>, // The slot of every variable use, by the use's span
+    pub diagnostics: Vec<EstaError>,
+}
+
+/// The declarations of one environment frame in the order they are laid out,
+/// along with the offset of each name
+#[derive(Debug, Default)]
+struct Frame {
+    decls: Vec<Identifier>,
+    names: HashMap<String, usize>,
+}
+
+/// The variables in view, as one frame per environment frame. The first frame
+/// is always the global one, which stays in view inside of functions. Frames
+/// are shared by every scope they are in view from.
+#[derive(Debug, Clone, Default)]
+pub struct Scopes {
+    frames: Vec<Rc<Frame>>,
+    hoisted: bool, // Whether every global is in view, as it is inside of functions
+}
+
+impl Scopes {
+    // A variable is only in view from its declaration onwards
+    fn find(&self, id: &Identifier) -> Option<(usize, usize)> {
+        self.frames.iter().enumerate().rev().find_map(|(i, frame)| {
+            let index = *frame.names.get(&id.id)?;
+            let global = i == 0 && self.hoisted;
+            if global || frame.decls[index].span.start <= id.span.start {
+                Some((self.frames.len() - 1 - i, index))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn resolve(&self, id: &Identifier) -> Option<Slot> {
+        let (depth, index) = self.find(id)?;
+        if depth == self.frames.len() - 1 {
+            Some(Slot::Global(index))
+        } else {
+            Some(Slot::Local { depth, index })
+        }
+    }
+
+    // Opens a frame holding decls, reporting any declared twice in it or
+    // hiding a variable that is already in view
+    fn push(&self, decls: Vec<Identifier>, diagnostics: &mut Vec<EstaError>) -> Scopes {
+        let mut frame = Frame::default();
+        for decl in decls {
+            if let Some(first) = frame.names.get(&decl.id).map(|&i| &frame.decls[i]) {
+                let message = format!(
+                    "{} is already declared in this scope at {}:{}",
+                    decl.id, first.span.start.line, first.span.start.column
+                );
+                diagnostics.push(EstaError::new(ErrorKind::Compile, message).with_span(decl.span));
+                continue;
+            }
+            if let Some((depth, index)) = self.find(&decl) {
+                let outer = &self.frames[self.frames.len() - 1 - depth].decls[index];
+                let message = format!(
+                    "{} shadows the variable declared at {}:{}",
+                    decl.id, outer.span.start.line, outer.span.start.column
+                );
+                diagnostics.push(EstaError::new(ErrorKind::Warning, message).with_span(decl.span));
+            }
+            frame.names.insert(decl.id.clone(), frame.decls.len());
+            frame.decls.push(decl);
+        }
+        let mut scopes = self.clone();
+        scopes.frames.push(Rc::new(frame));
+        scopes
+    }
+}
+
+/// # Name Resolution
+///
+/// Works out which declaration every variable use refers to and where that
+/// variable will live at runtime. This mirrors how the backend lays out
+/// environment frames: every scoped block gets a frame for the variables
+/// declared directly inside of it (or inside of unscoped blocks in it), every
+/// function gets a frame for its parameters and the top level statements share
+/// the global frame. Functions can't see the frames of the code around them,
+/// only the global one. A variable is in view from where it is declared until
+/// the end of its block, except that every global is in view inside functions,
+/// which may be called once the globals they use have been declared.
+pub struct Resolver;
+
+impl Resolver {
+    pub fn resolve(body: &Stmt) -> Resolution {
+        let mut diagnostics = Vec::new();
+        let globals = Scopes::default().push(Resolver::declarations(body), &mut diagnostics);
+        let mut resolution = Resolver::fold_stmt(&globals, body).unwrap_or_default();
+        diagnostics.append(&mut resolution.diagnostics);
+        resolution.diagnostics = diagnostics;
+        resolution
+    }
+
+    // Every variable declared in a block's frame, in the order they are declared
+    fn declarations(s: &Stmt) -> Vec<Identifier> {
+        match &s.kind {
+            StmtKind::Declaration(id) => vec![id.clone()],
+            StmtKind::Block(body, false) => {
+                body.iter().flat_map(|s| Self::declarations(s)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn resolve_id(down: &Scopes, id: &Identifier) -> Resolution {
+        let mut resolution = Resolution::default();
+        match down.resolve(id) {
+            Some(slot) => {
+                resolution.slots.insert(id.span, slot);
+            }
+            None => {
+                let message = format!("{} is not declared", id.id);
+                resolution
+                    .diagnostics
+                    .push(EstaError::compile(message).with_span(id.span));
+            }
+        }
+        resolution
+    }
+}
+
+impl Fold for Resolver {
+    type UpT = Resolution;
+    type DownT = Scopes;
+
+    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+        let mut resolution = Resolution::default();
+        for child in children.into_iter().flatten() {
+            resolution.slots.extend(child.slots);
+            resolution.diagnostics.extend(child.diagnostics);
+        }
+        Some(resolution)
+    }
+
+    fn fold_block(down: &Self::DownT, body: &[Box<Stmt>], is_scope: &bool) -> Option<Self::UpT> {
+        if !is_scope {
+            return Self::reduce(body.iter().map(|s| Self::fold_stmt(down, s)).collect());
+        }
+        let mut diagnostics = Vec::new();
+        let decls = body.iter().flat_map(|s| Self::declarations(s)).collect();
+        let scopes = down.push(decls, &mut diagnostics);
+        let children = body.iter().map(|s| Self::fold_stmt(&scopes, s)).collect();
+        let mut resolution = Self::reduce(children)?;
+        diagnostics.append(&mut resolution.diagnostics);
+        resolution.diagnostics = diagnostics;
+        Some(resolution)
+    }
+
+    fn fold_fundecl(
+        down: &Self::DownT,
+        _id: &Identifier,
+        params: &[Identifier],
+        body: &Stmt,
+    ) -> Option<Self::UpT> {
+        let mut diagnostics = Vec::new();
+        let globals = Scopes {
+            frames: down.frames[..1].to_vec(),
+            hoisted: true,
+        };
+        let scopes = globals.push(params.to_vec(), &mut diagnostics);
+        let mut resolution = Self::fold_stmt(&scopes, body)?;
+        diagnostics.append(&mut resolution.diagnostics);
+        resolution.diagnostics = diagnostics;
+        Some(resolution)
+    }
+
+    fn fold_id(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
+        Some(Resolver::resolve_id(down, id))
+    }
+
+    // Only the struct is a variable, a field is looked up at runtime
+    fn fold_dot(down: &Self::DownT, this: &Identifier, action: &Expr) -> Option<Self::UpT> {
+        let this = Some(Resolver::resolve_id(down, this));
+        match &action.kind {
+            ExprKind::Id(_) => this,
+            _ => Self::reduce(vec![this, Self::fold_expr(down, action)]),
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use crate::error::{ErrorKind, EstaError, Position};
+use crate::frontend::ast::*;
+use crate::{frontend, middleend};
+
+fn diagnostics(input: &str) -> Vec<EstaError> {
+    let stmts = frontend::run(input).unwrap();
+    match middleend::run(stmts) {
+        Ok((_, md)) => md.warnings,
+        Err(errors) => errors,
+    }
+}
+
+#[test]
+fn test_undeclared() {
+    let errors = diagnostics("var a = 1;\na = b + 1;");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, ErrorKind::Compile);
+    assert_eq!(errors[0].message, "b is not declared");
+    assert_eq!(
+        errors[0].span.unwrap().start,
+        Position { line: 2, column: 5 }
+    );
+
+    // Variables declared in a block are gone once it ends, and functions can
+    // only see their own variables and the globals
+    let input = "
+        var g;
+        if True { var a; }
+        a = 1;
+        fun f(x) { var y = x + g; return z; }
+        fun h() { return x.field; }
+        var z;";
+    let errors = diagnostics(input);
+    let lines: Vec<usize> = errors.iter().map(|e| e.span.unwrap().start.line).collect();
+    assert_eq!(lines, vec![4, 6]);
+
+    assert!(diagnostics("var a; fun f(b) { a = b; a.x = b[0]; }").is_empty());
+
+    // Variables are only in view once they are declared
+    let errors = diagnostics("print(x);\nvar x = 1;\nif True { x = y; var y; }");
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec!["x is not declared", "y is not declared"]);
+}
+
+#[test]
+fn test_duplicates() {
+    let errors = diagnostics("var a;\nvar a = 2;");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, ErrorKind::Compile);
+    assert_eq!(
+        errors[0].message,
+        "a is already declared in this scope at 1:5"
+    );
+
+    let errors = diagnostics("fun f(a, b, a) {}");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].span.unwrap().start.column, 13);
+
+    // A for loop's variable lives in a scope of its own
+    assert!(
+        diagnostics("for var i = 0; i < 2; i = i + 1; {} for var i = 0; i < 2; i = i + 1; {}")
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_shadowing() {
+    let input = "
+        var a;
+        fun f(a) { var b; if a { var b; } }
+        if True { var a; }";
+    let warnings = diagnostics(input);
+    assert!(warnings.iter().all(|w| w.kind == ErrorKind::Warning));
+    let lines: Vec<usize> = warnings
+        .iter()
+        .map(|w| w.span.unwrap().start.line)
+        .collect();
+    assert_eq!(lines, vec![3, 3, 4]);
+    assert_eq!(
+        warnings[0].message,
+        "a shadows the variable declared at 2:13"
+    );
+}
+
+#[test]
+fn test_arity() {
+    let input = "
+        struct Point { x, y }
+        fun add(a, b) { return a + b; }
+        fun main() {
+            var p = Point();
+            add(1, add(2));
+            print(1, 2, 3);
+            len();
+            missing(p);
+            var q = p.move(missing(1));
+        }";
+    let errors = diagnostics(input);
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "add takes 2 arguments but 1 was given",
+            "len takes 1 argument but 0 were given",
+            "missing is not a function",
+            "missing is not a function",
+        ]
+    );
+    assert_eq!(
+        errors[0].span.unwrap().start,
+        Position {
+            line: 6,
+            column: 20
+        }
+    );
+    assert!(errors.iter().all(|e| e.kind == ErrorKind::Compile));
+
+    // Functions and structs share one namespace
+    let input = "
+        struct Point { x }
+        fun f() { fun g() {} }
+        fun g(a) {}
+        fun Point() {}
+        fun f() {}";
+    let errors = diagnostics(input);
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "g is already declared at 3:23",
+            "Point is already declared at 2:9",
+            "f is already declared at 3:13",
+        ]
+    );
+    assert_eq!(errors[0].span.unwrap().start.line, 4);
+
+    // Functions can be called before they are declared, and from inside each other
+    assert!(diagnostics("fun f() { return g(1); } fun g(x) { fun h() {} h(); }").is_empty());
+}
+
+#[test]
+fn test_types() {
+    // Dynamic values fit anywhere
+    let input = "
+        var a;
+        a = 1;
+        a = \"now a string\";
+        fun f(x, y) { return x + y; }
+        var b = f(a, [1]) - 2;";
+    assert!(diagnostics(input).is_empty());
+
+    let input = "
+        var a: num = 4;
+        var b;
+        var c = a + b;
+        var s: str = 1;
+        if a { }
+        while not c { }
+        var n = -\"x\" * True;";
+    let errors = diagnostics(input);
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Expected str but found num",
+            "Expected bool but found num",
+            "Expected bool but found num",
+            "Expected num but found str",
+            "Expected num but found bool",
+        ]
+    );
+    assert_eq!(
+        errors[0].span.unwrap().start,
+        Position {
+            line: 5,
+            column: 22
+        }
+    );
+    assert!(errors.iter().all(|e| e.kind == ErrorKind::Compile));
+
+    // Returning nothing, or falling off the end of the body, returns Nil
+    let input = "
+        fun f() -> num { return; }
+        fun g(x) -> num { if x { return 1; } }
+        fun h(x) -> num { if x { return 1; } else { return 2; } }
+        fun k() -> nil { }";
+    let errors = diagnostics(input);
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec!["Expected num but found nil", "Expected num but found nil"]
+    );
+    assert_eq!(errors[1].span.unwrap().start.line, 3);
+
+    let errors = diagnostics("var a: number; var b = 1 + \"x\";");
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec!["Unknown type number", "Can't add num and str"]
+    );
+}
+
+#[test]
+fn test_types_reassigned() {
+    // Unannotated variables are read as what was last assigned to them, but may
+    // be assigned anything
+    let input = "
+        fun main() {
+            var x = Nil;
+            x = 5;
+            var y = x + 1;
+            x = \"five\";
+            y = x + \"!\";
+            if x != Nil { return x; }
+            return y;
+        }";
+    assert!(diagnostics(input).is_empty());
+
+    // After a branch or in a loop that may assign to a variable, it could be either
+    let input = "
+        var a = 1;
+        var b = 1;
+        if a > 0 { a = \"one\"; }
+        while b < 5 { b = b + 1; }
+        var c = -a + -b;
+        var d = 1;
+        d = \"one\";
+        var e = -d;";
+    let errors = diagnostics(input);
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec!["Expected num but found str"]);
+    assert_eq!(errors[0].span.unwrap().start.line, 9);
+
+    // Any function called may have assigned to a global
+    let input = "
+        fun f() { g = \"a\"; }
+        var g = 1;
+        f();
+        print(g + \"b\");
+        g = 2;
+        var h = [f(), g + \"c\"];";
+    assert!(diagnostics(input).is_empty());
+
+    // Annotations still limit what may be assigned
+    let errors = diagnostics("var a: num = 1; a = \"one\";");
+    assert_eq!(errors[0].message, "Expected num but found str");
+}
+
+#[test]
+fn test_types_functions() {
+    let input = "
+        struct Point { x: num, y }
+        fun scale(p: Point, by: num) -> num {
+            p.y = \"anything\";
+            p.x = \"not a num\";
+            return p.x * by + p.z;
+        }
+        fun main() -> str {
+            var p = Point();
+            var n = scale(p, True) + 1;
+            n.x = 1;
+            return n;
+        }";
+    let errors = diagnostics(input);
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Expected num but found str",
+            "Point has no field z",
+            "Expected num but found bool",
+            "num has no fields",
+            "Expected str but found num",
+        ]
+    );
+}
+
+#[test]
+fn test_strictness() {
+    use crate::middleend::typecheck::Strictness;
+
+    let check = |strictness| {
+        let stmts = frontend::run("var a: num = True;").unwrap();
+        let settings = middleend::Settings {
+            strictness,
+            ..Default::default()
+        };
+        middleend::run_with(stmts, &settings).map(|(_, md)| md.warnings)
+    };
+    assert_eq!(
+        check(Strictness::Deny).unwrap_err()[0].kind,
+        ErrorKind::Compile
+    );
+    let warnings = check(Strictness::Warn).unwrap();
+    assert_eq!(warnings[0].kind, ErrorKind::Warning);
+    assert_eq!(warnings[0].message, "Expected num but found bool");
+    assert!(check(Strictness::Ignore).unwrap().is_empty());
+}
+
+// Every statement nested anywhere in s, s included
+fn walk(s: &Stmt) -> Vec<&Stmt> {
+    let children: Vec<&Stmt> = match &s.kind {
+        StmtKind::Block(body, _) => body.iter().map(|s| s.as_ref()).collect(),
+        StmtKind::If(_, body, alter) => vec![body, alter],
+        StmtKind::While(_, body) | StmtKind::FunDecl(_, _, body) => vec![body],
+        _ => Vec::new(),
+    };
+    std::iter::once(s)
+        .chain(children.into_iter().flat_map(walk))
+        .collect()
+}
+
+// Every value assigned or returned anywhere in s
+fn values(s: &Stmt) -> Vec<&ExprKind> {
+    walk(s)
+        .into_iter()
+        .filter_map(|s| match &s.kind {
+            StmtKind::Assignment(_, rhs) | StmtKind::Return(Some(rhs)) => Some(&rhs.kind),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_optimize() {
+    let optimized = |input: &str| {
+        let stmts = frontend::run(input).unwrap();
+        middleend::run(stmts).unwrap().0
+    };
+    let number = |e: &ExprKind| match e {
+        ExprKind::Literal(Literal::Number(n)) => Some(*n),
+        _ => None,
+    };
+
+    let folded =
+        optimized("var a = 1 + 2 * 3 - -4; var b = \"x\" + \"y\" == \"xy\" and not False;");
+    match &values(&folded)[..] {
+        [ExprKind::Literal(Literal::Number(11)), ExprKind::Literal(Literal::Boolean(true))] => {}
+        values => panic!("Not folded: {:?}", values),
+    }
+
+    // Operations that fail at runtime are left for the VM to report
+    let kept = optimized("var a = 1 / 0;");
+    assert!(matches!(
+        values(&kept)[..],
+        [ExprKind::BinaryOp(_, Opcode::Div, _)]
+    ));
+
+    let branches = optimized("var a; if True { a = 1; } else { a = 2; } while 1 > 2 { a = 3; }");
+    assert!(walk(&branches)
+        .iter()
+        .all(|s| !matches!(s.kind, StmtKind::If(..) | StmtKind::While(..))));
+    let assigned: Vec<Option<i32>> = values(&branches).into_iter().map(number).collect();
+    assert_eq!(assigned, vec![Some(1)]);
+
+    // Unreachable code goes, but the functions and variables it declares stay
+    let dead = optimized("fun f() { return 1; g(); var x; x = 2; fun g() {} }");
+    let returned: Vec<Option<i32>> = values(&dead).into_iter().map(number).collect();
+    assert_eq!(returned, vec![Some(1)]);
+    let stmts = walk(&dead);
+    assert!(stmts
+        .iter()
+        .any(|s| matches!(&s.kind, StmtKind::Declaration(id) if id.id == "x")));
+    assert!(stmts
+        .iter()
+        .any(|s| matches!(&s.kind, StmtKind::FunDecl(id, _, _) if id.id == "g")));
+
+    let kept = optimized("fun f(a) { if a { return 1; } return 2; }");
+    assert!(walk(&kept)
+        .iter()
+        .any(|s| matches!(s.kind, StmtKind::If(..))));
+    let returned: Vec<Option<i32>> = values(&kept).into_iter().map(number).collect();
+    assert_eq!(returned, vec![Some(1), Some(2)]);
+}
@@ -0,0 +1,688 @@
+use crate::error::{ErrorKind, EstaError, Position, Span};
+use crate::frontend::ast::*;
+use crate::util::fold::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// The static type of a value. A Dynamic value may hold anything, so it is
+/// consistent with every other type and is left for the VM to check.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Type {
+    #[default]
+    Dynamic,
+    Num,
+    Bool,
+    Str,
+    List,
+    Nil,
+    Struct(String),
+}
+
+impl Type {
+    pub fn consistent(&self, other: &Type) -> bool {
+        *self == Type::Dynamic || *other == Type::Dynamic || self == other
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Dynamic => write!(f, "Dynamic"),
+            Type::Num => write!(f, "num"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "str"),
+            Type::List => write!(f, "list"),
+            Type::Nil => write!(f, "nil"),
+            Type::Struct(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+/// How type errors are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    Ignore, // Type errors are never reported
+    Warn,   // Type errors are reported as warnings
+    #[default]
+    Deny, // Type errors fail compilation
+}
+
+/// The types of the variables in view, one list per environment frame with the
+/// global frame first, along with the signatures of every function and struct.
+#[derive(Debug, Clone, Default)]
+pub struct TypeEnv {
+    frames: Vec<HashMap<String, Var>>,
+    hoisted: bool,     // Whether every global is in view, as it is inside of functions
+    ret: Option<Type>, // The return type of the function being checked
+    functions: Rc<HashMap<String, (Vec<Type>, Type)>>,
+    structs: Rc<HashMap<String, HashMap<String, Type>>>,
+}
+
+/// A variable's declared type is what it is annotated with, which every value
+/// assigned to it must fit. An unannotated variable is Dynamic, but it still
+/// keeps track of the type of the last value assigned to it. That type is only
+/// a hint of what reading the variable gives, and never limits what it holds.
+#[derive(Debug, Clone)]
+struct Var {
+    declared: Type,
+    inferred: Type,
+    at: Position, // Where it is declared, as it is only in view from there on
+}
+
+impl TypeEnv {
+    // Struct names are types too, so they must all be known before any of the
+    // annotations in the signatures are read
+    fn new(
+        structs: &[(String, Vec<Identifier>)],
+        functions: &[(Identifier, Vec<Identifier>)],
+    ) -> TypeEnv {
+        let names = structs.iter().map(|(id, _)| (id.clone(), HashMap::new()));
+        let names = TypeEnv {
+            structs: Rc::new(names.collect()),
+            ..Default::default()
+        };
+        let types = |ids: &[Identifier]| -> Vec<Type> {
+            ids.iter()
+                .map(|id| names.annotation(&id.type_of).unwrap_or_default())
+                .collect()
+        };
+
+        let structs = structs.iter().map(|(id, fields)| {
+            let names = fields.iter().map(|f| f.id.clone());
+            (id.clone(), names.zip(types(fields)).collect())
+        });
+        let functions = functions.iter().map(|(id, params)| {
+            let ret = names.annotation(&id.type_of).unwrap_or_default();
+            (id.id.clone(), (types(params), ret))
+        });
+        TypeEnv {
+            structs: Rc::new(structs.collect()),
+            functions: Rc::new(functions.collect()),
+            ..Default::default()
+        }
+    }
+
+    /// The type an annotation names, if there is such a type
+    fn annotation(&self, type_of: &str) -> Option<Type> {
+        match type_of {
+            "Dynamic" => Some(Type::Dynamic),
+            "num" => Some(Type::Num),
+            "bool" => Some(Type::Bool),
+            "str" => Some(Type::Str),
+            "list" => Some(Type::List),
+            "nil" => Some(Type::Nil),
+            id if self.structs.contains_key(id) => Some(Type::Struct(id.to_string())),
+            _ => None,
+        }
+    }
+
+    // The frame of the innermost variable named id in view where it is used
+    fn frame(&self, id: &Identifier) -> Option<usize> {
+        (0..self.frames.len())
+            .rev()
+            .find(|&i| match self.frames[i].get(&id.id) {
+                Some(var) => (i == 0 && self.hoisted) || var.at <= id.span.start,
+                None => false,
+            })
+    }
+
+    fn var(&self, id: &Identifier) -> Option<&Var> {
+        self.frames[self.frame(id)?].get(&id.id)
+    }
+
+    /// The type of the value read from a variable
+    fn lookup(&self, id: &Identifier) -> Type {
+        match self.var(id) {
+            Some(var) if var.declared == Type::Dynamic => var.inferred.clone(),
+            Some(var) => var.declared.clone(),
+            None => Type::Dynamic,
+        }
+    }
+
+    /// The type every value assigned to a variable must fit
+    fn declared(&self, id: &Identifier) -> Type {
+        self.var(id)
+            .map(|var| var.declared.clone())
+            .unwrap_or_default()
+    }
+
+    // Gives the variable id refers to the type of the value last assigned to it
+    fn infer(&mut self, id: &Identifier, ty: Type) {
+        if let Some(frame) = self.frame(id) {
+            if let Some(var) = self.frames[frame].get_mut(&id.id) {
+                var.inferred = ty;
+            }
+        }
+    }
+
+    fn push(&self, decls: &[Identifier]) -> TypeEnv {
+        let frame = decls
+            .iter()
+            .map(|d| {
+                let var = Var {
+                    declared: self.annotation(&d.type_of).unwrap_or_default(),
+                    inferred: Type::Dynamic,
+                    at: d.span.start,
+                };
+                (d.id.clone(), var)
+            })
+            .collect();
+        let mut env = self.clone();
+        env.frames.push(frame);
+        env
+    }
+
+    // Any function may assign to the globals, so after a call only their
+    // annotations are known
+    fn forget_globals(&mut self) {
+        for var in self.frames.iter_mut().take(1).flat_map(|f| f.values_mut()) {
+            var.inferred = Type::Dynamic;
+        }
+    }
+
+    // Inside of a function, only the globals and its own parameters are in view
+    fn function(&self, ret: &Identifier, params: &[Identifier]) -> TypeEnv {
+        let mut globals = TypeEnv {
+            frames: self.frames[..1].to_vec(),
+            hoisted: true,
+            ret: Some(self.annotation(&ret.type_of).unwrap_or_default()),
+            ..self.clone()
+        };
+        globals.forget_globals();
+        globals.push(params)
+    }
+}
+
+/// The type of an expression along with everything found while checking it
+#[derive(Debug, Clone, Default)]
+pub struct Typed {
+    ty: Type,
+    diagnostics: Vec<EstaError>,
+    guards: Vec<(Span, Type)>, // Values to check at runtime, with the type they must have
+    inferred: Vec<(Identifier, Type)>, // Types of the values last assigned to unannotated variables
+}
+
+impl Typed {
+    fn new(ty: Type) -> Typed {
+        Typed {
+            ty,
+            ..Default::default()
+        }
+    }
+
+    // Takes on everything found while checking a child, handing back its type
+    fn absorb(&mut self, child: Option<Typed>) -> Type {
+        let child = child.unwrap_or_default();
+        self.diagnostics.extend(child.diagnostics);
+        self.guards.extend(child.guards);
+        self.inferred.extend(child.inferred);
+        child.ty
+    }
+
+    fn error(&mut self, message: String, span: Span) {
+        self.diagnostics
+            .push(EstaError::compile(message).with_span(span));
+    }
+
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span) {
+        if !expected.consistent(found) {
+            self.error(format!("Expected {} but found {}", expected, found), span);
+        }
+    }
+
+    // Where a value flows into something annotated, it is also checked at
+    // runtime unless it is already known to have the annotated type. Expected is
+    // always an annotation, as an inferred type never limits a variable.
+    fn guard(&mut self, expected: &Type, found: &Type, span: Span) {
+        self.expect(expected, found, span);
+        if *expected != Type::Dynamic && expected != found {
+            self.guards.push((span, expected.clone()));
+        }
+    }
+
+    fn annotation(&mut self, env: &TypeEnv, id: &Identifier) {
+        if env.annotation(&id.type_of).is_none() {
+            self.error(format!("Unknown type {}", id.type_of), id.span);
+        }
+    }
+}
+
+/// Traverses the AST searching for the signatures of every struct and function
+struct SignatureCollector;
+
+type Signatures = (
+    Vec<(String, Vec<Identifier>)>,
+    Vec<(Identifier, Vec<Identifier>)>,
+);
+
+impl Fold for SignatureCollector {
+    type UpT = Signatures;
+    type DownT = ();
+
+    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+        let (structs, functions) = children
+            .into_iter()
+            .flatten()
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+        Some((
+            structs.into_iter().flatten().collect(),
+            functions.into_iter().flatten().collect(),
+        ))
+    }
+
+    fn fold_struct(_down: &Self::DownT, id: &str, fields: &[Identifier]) -> Option<Self::UpT> {
+        Some((vec![(id.to_string(), fields.to_vec())], Vec::new()))
+    }
+
+    fn fold_fundecl(
+        down: &Self::DownT,
+        id: &Identifier,
+        params: &[Identifier],
+        body: &Stmt,
+    ) -> Option<Self::UpT> {
+        let signature = Some((Vec::new(), vec![(id.clone(), params.to_vec())]));
+        Self::reduce(vec![signature, Self::fold_stmt(down, body)])
+    }
+}
+
+/// Traverses a statement searching for calls to declared functions, leaving out
+/// the bodies of the functions it declares
+struct CallFinder;
+
+impl Fold for CallFinder {
+    type UpT = bool;
+    type DownT = TypeEnv;
+
+    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+        Some(children.into_iter().flatten().any(|c| c))
+    }
+
+    fn fold_fundecl(
+        _down: &Self::DownT,
+        _id: &Identifier,
+        _params: &[Identifier],
+        _body: &Stmt,
+    ) -> Option<Self::UpT> {
+        None
+    }
+
+    fn fold_funcall(down: &Self::DownT, id: &Identifier, args: &[Expr]) -> Option<Self::UpT> {
+        let args = args.iter().map(|e| Self::fold_expr(down, e)).collect();
+        Some(down.functions.contains_key(&id.id) || Self::reduce(args)?)
+    }
+}
+
+/// # Gradual Type Checking
+///
+/// Variables, parameters, struct fields and function returns may be annotated
+/// with a type, as in `var a: num`. Anything left unannotated is Dynamic, which
+/// fits wherever any other type is expected. Reading a Dynamic variable gives
+/// the type of the value last assigned to it, so in
+/// > var a: num = 4;
+/// > var c = a + b;
+///
+/// c is read as a num, since adding anything to a num gives a num. It can still
+/// be assigned a value of any other type later on. Only a variable assigned to
+/// in a loop is read as Dynamic throughout the loop, as is a variable after a
+/// block that may or may not have assigned to it, and a global in and after a
+/// statement that calls a function.
+///
+/// Operators, conditions, assignments, returned values and call arguments are
+/// all checked against the types they expect. Type errors fail compilation or
+/// are reported as warnings depending on the strictness. Whenever a value that
+/// isn't known to have the right type flows into an annotated variable, field,
+/// parameter or return, it is guarded, which makes the VM check it at runtime.
+pub struct TypeChecker;
+
+impl TypeChecker {
+    /// Returns every type error, along with the span of every value that has to
+    /// be checked at runtime and the type it must have
+    pub fn check(body: &Stmt, strictness: Strictness) -> (Vec<EstaError>, HashMap<Span, Type>) {
+        let (structs, functions) = SignatureCollector::fold_stmt(&(), body).unwrap_or_default();
+        let env = TypeEnv::new(&structs, &functions);
+        let globals = env.push(&TypeChecker::declarations(body));
+        let typed = TypeChecker::fold_stmt(&globals, body).unwrap_or_default();
+
+        let diagnostics = match strictness {
+            Strictness::Ignore => Vec::new(),
+            Strictness::Warn => typed
+                .diagnostics
+                .into_iter()
+                .map(|d| EstaError {
+                    kind: ErrorKind::Warning,
+                    ..d
+                })
+                .collect(),
+            Strictness::Deny => typed.diagnostics,
+        };
+        (diagnostics, typed.guards.into_iter().collect())
+    }
+
+    // Every variable declared in a block's frame, in the order they are declared
+    fn declarations(s: &Stmt) -> Vec<Identifier> {
+        match &s.kind {
+            StmtKind::Declaration(id) => vec![id.clone()],
+            StmtKind::Block(body, false) => {
+                body.iter().flat_map(|s| Self::declarations(s)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Whether running a statement may carry on past its end. A function body that
+    // does returns Nil from there.
+    fn falls_through(s: &Stmt) -> bool {
+        match &s.kind {
+            StmtKind::Return(_) => false,
+            StmtKind::Block(body, _) => body.iter().all(|s| TypeChecker::falls_through(s)),
+            StmtKind::If(_, body, alter) => {
+                TypeChecker::falls_through(body) || TypeChecker::falls_through(alter)
+            }
+            _ => true,
+        }
+    }
+
+    fn field(env: &TypeEnv, this: &Identifier, field: &Identifier) -> Typed {
+        let mut typed = Typed::default();
+        typed.ty = match env.lookup(this) {
+            Type::Dynamic => Type::Dynamic,
+            Type::Struct(id) => match env.structs[&id].get(&field.id) {
+                Some(ty) => ty.clone(),
+                None => {
+                    typed.error(format!("{} has no field {}", id, field.id), field.span);
+                    Type::Dynamic
+                }
+            },
+            ty => {
+                typed.error(format!("{} has no fields", ty), this.span);
+                Type::Dynamic
+            }
+        };
+        typed
+    }
+}
+
+impl Fold for TypeChecker {
+    type UpT = Typed;
+    type DownT = TypeEnv;
+
+    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        for child in children.into_iter() {
+            typed.absorb(child);
+        }
+        Some(typed)
+    }
+
+    // Statements are checked in order, so that every variable assigned a value
+    // is read as its type by the statements after it. An unscoped block only
+    // copies the environment once a statement in it changes what comes after.
+    fn fold_block(down: &Self::DownT, body: &[Box<Stmt>], is_scope: &bool) -> Option<Self::UpT> {
+        let decls: Vec<Identifier> = match is_scope {
+            true => body.iter().flat_map(|s| Self::declarations(s)).collect(),
+            false => Vec::new(),
+        };
+        let mut env = match is_scope {
+            true => Cow::Owned(down.push(&decls)),
+            false => Cow::Borrowed(down),
+        };
+
+        let mut typed = Typed::default();
+        let mut applied = 0;
+        for s in body.iter() {
+            for (id, ty) in typed.inferred[applied..].iter() {
+                env.to_mut().infer(id, ty.clone());
+            }
+            applied = typed.inferred.len();
+            if CallFinder::fold_stmt(&env, s).unwrap_or_default() {
+                env.to_mut().forget_globals();
+            }
+            typed.absorb(Self::fold_stmt(&env, s));
+        }
+
+        // The variables of a scoped block are gone once it ends. A scoped block may
+        // not run at all, so variables from further out that it assigned to could
+        // hold either their old value or the new one after it.
+        if *is_scope {
+            let mut inferred: Vec<(Identifier, Type)> = Vec::new();
+            for (id, _) in typed.inferred.drain(..) {
+                let local = decls
+                    .iter()
+                    .any(|d| d.id == id.id && d.span.start <= id.span.start);
+                if !local && inferred.iter().all(|(i, _)| i.id != id.id) {
+                    inferred.push((id, Type::Dynamic));
+                }
+            }
+            typed.inferred = inferred;
+        }
+        Some(typed)
+    }
+
+    fn fold_if(down: &Self::DownT, test: &Expr, body: &Stmt, alter: &Stmt) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        let cond = typed.absorb(Self::fold_expr(down, test));
+        typed.expect(&Type::Bool, &cond, test.span);
+        typed.absorb(Self::fold_stmt(down, body));
+        typed.absorb(Self::fold_stmt(down, alter));
+        Some(typed)
+    }
+
+    // The body is checked once to find the variables it assigns to, which may hold
+    // a value from the last iteration whenever the test or the body reads them
+    fn fold_while(down: &Self::DownT, test: &Expr, body: &Stmt) -> Option<Self::UpT> {
+        let mut env = down.clone();
+        for (id, _) in Self::fold_stmt(down, body).unwrap_or_default().inferred {
+            env.infer(&id, Type::Dynamic);
+        }
+
+        let mut typed = Typed::default();
+        let cond = typed.absorb(Self::fold_expr(&env, test));
+        typed.expect(&Type::Bool, &cond, test.span);
+        typed.absorb(Self::fold_stmt(&env, body));
+        Some(typed)
+    }
+
+    // A bare return gives back Nil, which is checked against the function's
+    // annotation at the span of the statement itself
+    fn fold_stmt(down: &Self::DownT, s: &Stmt) -> Option<Self::UpT> {
+        match (&s.kind, &down.ret) {
+            (StmtKind::Return(None), Some(ret)) => {
+                let mut typed = Typed::default();
+                typed.guard(ret, &Type::Nil, s.span);
+                Some(typed)
+            }
+            _ => Self::walk_stmt(down, s),
+        }
+    }
+
+    fn fold_return(down: &Self::DownT, value: &Option<Box<Expr>>) -> Option<Self::UpT> {
+        let value = value.as_ref()?;
+        let mut typed = Typed::default();
+        let ty = typed.absorb(Self::fold_expr(down, value));
+        if let Some(ret) = &down.ret {
+            typed.guard(ret, &ty, value.span);
+        }
+        Some(typed)
+    }
+
+    fn fold_declaration(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        typed.annotation(down, id);
+        Some(typed)
+    }
+
+    fn fold_fundecl(
+        down: &Self::DownT,
+        id: &Identifier,
+        params: &[Identifier],
+        body: &Stmt,
+    ) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        for decl in std::iter::once(id).chain(params.iter()) {
+            typed.annotation(down, decl);
+        }
+        let inner = down.function(id, params);
+        typed.absorb(Self::fold_stmt(&inner, body));
+        if TypeChecker::falls_through(body) {
+            if let Some(ret) = &inner.ret {
+                typed.guard(ret, &Type::Nil, id.span);
+            }
+        }
+        Some(typed)
+    }
+
+    // A value assigned to a variable must fit its annotation, not whatever the
+    // variable was assigned before. Unannotated, it is read as the value's type.
+    fn fold_assignment(down: &Self::DownT, lhs: &Expr, rhs: &Expr) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        let ty = typed.absorb(Self::fold_expr(down, rhs));
+        let target = match &lhs.kind {
+            ExprKind::Id(id) => {
+                let declared = down.declared(id);
+                if declared == Type::Dynamic {
+                    typed.inferred.push((id.clone(), ty.clone()));
+                }
+                declared
+            }
+            // A bare procedure call statement is parsed as an assignment to Nil
+            ExprKind::Literal(Literal::Nil) => Type::Dynamic,
+            _ => typed.absorb(Self::fold_expr(down, lhs)),
+        };
+        typed.guard(&target, &ty, rhs.span);
+        typed.ty = ty;
+        Some(typed)
+    }
+
+    fn fold_struct(down: &Self::DownT, _id: &str, fields: &[Identifier]) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        for field in fields.iter() {
+            typed.annotation(down, field);
+        }
+        Some(typed)
+    }
+
+    fn fold_id(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
+        Some(Typed::new(down.lookup(id)))
+    }
+
+    fn fold_literal(_down: &Self::DownT, lit: &Literal) -> Option<Self::UpT> {
+        let ty = match lit {
+            Literal::Number(_) => Type::Num,
+            Literal::Boolean(_) => Type::Bool,
+            Literal::String(_) => Type::Str,
+            Literal::Nil => Type::Nil,
+        };
+        Some(Typed::new(ty))
+    }
+
+    // Adding a Dynamic value to a num or a str can only give the same type back
+    fn fold_binary(down: &Self::DownT, lhs: &Expr, op: &Opcode, rhs: &Expr) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        let lty = typed.absorb(Self::fold_expr(down, lhs));
+        let rty = typed.absorb(Self::fold_expr(down, rhs));
+        let operands = |typed: &mut Typed, ty: &Type| {
+            typed.expect(ty, &lty, lhs.span);
+            typed.expect(ty, &rty, rhs.span);
+        };
+
+        typed.ty = match op {
+            Opcode::Add => match (&lty, &rty) {
+                (Type::Dynamic, Type::Dynamic) => Type::Dynamic,
+                (Type::Num, ty) | (ty, Type::Num) if Type::Num.consistent(ty) => Type::Num,
+                (Type::Str, ty) | (ty, Type::Str) if Type::Str.consistent(ty) => Type::Str,
+                (lty, rty) => {
+                    let message = format!("Can't add {} and {}", lty, rty);
+                    typed.error(message, lhs.span.to(rhs.span));
+                    Type::Dynamic
+                }
+            },
+            Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod => {
+                operands(&mut typed, &Type::Num);
+                Type::Num
+            }
+            Opcode::Greater | Opcode::GreaterEqual | Opcode::Lesser | Opcode::LesserEqual => {
+                operands(&mut typed, &Type::Num);
+                Type::Bool
+            }
+            // Unless it short circuits, a logical operator gives back its rhs as is
+            Opcode::And | Opcode::Or => {
+                operands(&mut typed, &Type::Bool);
+                match rty {
+                    Type::Bool => Type::Bool,
+                    _ => Type::Dynamic,
+                }
+            }
+            _ => Type::Bool,
+        };
+        Some(typed)
+    }
+
+    fn fold_unary(down: &Self::DownT, op: &Opcode, rhs: &Expr) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        let found = typed.absorb(Self::fold_expr(down, rhs));
+        let ty = match op {
+            Opcode::Not => Type::Bool,
+            _ => Type::Num,
+        };
+        typed.expect(&ty, &found, rhs.span);
+        typed.ty = ty;
+        Some(typed)
+    }
+
+    // Natives are untyped, so only calls to declared functions are checked
+    fn fold_funcall(down: &Self::DownT, id: &Identifier, args: &[Expr]) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        let args: Vec<(Type, Span)> = args
+            .iter()
+            .map(|e| (typed.absorb(Self::fold_expr(down, e)), e.span))
+            .collect();
+
+        typed.ty = if down.structs.contains_key(&id.id) {
+            Type::Struct(id.id.clone())
+        } else if let Some((params, ret)) = down.functions.get(&id.id) {
+            for (param, (arg, span)) in params.iter().zip(args.iter()) {
+                typed.guard(param, arg, *span);
+            }
+            ret.clone()
+        } else {
+            Type::Dynamic
+        };
+        Some(typed)
+    }
+
+    fn fold_list(down: &Self::DownT, xs: &[Box<Expr>]) -> Option<Self::UpT> {
+        let mut typed = Self::reduce(xs.iter().map(|e| Self::fold_expr(down, e)).collect())?;
+        typed.ty = Type::List;
+        Some(typed)
+    }
+
+    fn fold_dot(down: &Self::DownT, this: &Identifier, action: &Expr) -> Option<Self::UpT> {
+        match &action.kind {
+            ExprKind::Id(field) => Some(TypeChecker::field(down, this, field)),
+            // Methods are looked up at runtime, so only their arguments are checked
+            ExprKind::FunCall(_, args) => {
+                Self::reduce(args.iter().map(|e| Self::fold_expr(down, e)).collect())
+            }
+            _ => Self::fold_expr(down, action),
+        }
+    }
+
+    fn fold_index(down: &Self::DownT, xs: &Expr, idx: &Expr) -> Option<Self::UpT> {
+        let mut typed = Typed::default();
+        let list = typed.absorb(Self::fold_expr(down, xs));
+        let index = typed.absorb(Self::fold_expr(down, idx));
+        typed.ty = match list {
+            Type::Str => Type::Str,
+            Type::List | Type::Dynamic => Type::Dynamic,
+            ty => {
+                let message = format!("Only strings and lists can be indexed, found {}", ty);
+                typed.error(message, xs.span);
+                Type::Dynamic
+            }
+        };
+        typed.expect(&Type::Num, &index, idx.span);
+        Some(typed)
+    }
+}
@@ -24,17 +24,17 @@ impl Fold for TypeCollector {
     type DownT = ();
 
     fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
-        if children.len() > 0 {
+        if !children.is_empty() {
             Some(children.into_iter().flatten().flatten().collect())
         } else {
             None
         }
     }
 
-    fn fold_struct(_: &Self::DownT, id: &String, fields: &Vec<Identifier>) -> Option<Self::UpT> {
-        Some(vec![EstaStruct::new(Stmt::Struct(
-            id.clone(),
-            fields.clone(),
+    fn fold_struct(_: &Self::DownT, id: &str, fields: &[Identifier]) -> Option<Self::UpT> {
+        Some(vec![EstaStruct::new(StmtKind::Struct(
+            id.to_string(),
+            fields.to_vec(),
         ))])
     }
 }
@@ -12,21 +12,16 @@ pub trait Fold {
     type UpT; // This value is passed down before the traversal
     type DownT; // This value is passed up after the traversal
 
-    fn reduce(children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
+    fn reduce(_children: Vec<Option<Self::UpT>>) -> Option<Self::UpT> {
         None
     }
 
-    fn fold_block(down: &Self::DownT, body: &Vec<Box<Stmt>>, is_scope: &bool) -> Option<Self::UpT> {
+    fn fold_block(down: &Self::DownT, body: &[Box<Stmt>], _is_scope: &bool) -> Option<Self::UpT> {
         let children = body.iter().map(|b| Self::fold_stmt(down, b)).collect();
         Self::reduce(children)
     }
 
-    fn fold_if(
-        down: &Self::DownT,
-        test: &Box<Expr>,
-        body: &Box<Stmt>,
-        alter: &Box<Stmt>,
-    ) -> Option<Self::UpT> {
+    fn fold_if(down: &Self::DownT, test: &Expr, body: &Stmt, alter: &Stmt) -> Option<Self::UpT> {
         let children = vec![
             Self::fold_expr(down, test),
             Self::fold_stmt(down, body),
@@ -35,7 +30,7 @@ pub trait Fold {
         Self::reduce(children)
     }
 
-    fn fold_while(down: &Self::DownT, test: &Box<Expr>, body: &Box<Stmt>) -> Option<Self::UpT> {
+    fn fold_while(down: &Self::DownT, test: &Expr, body: &Stmt) -> Option<Self::UpT> {
         let children = vec![Self::fold_expr(down, test), Self::fold_stmt(down, body)];
         Self::reduce(children)
     }
@@ -48,20 +43,20 @@ pub trait Fold {
         }
     }
 
-    fn fold_declaration(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
+    fn fold_declaration(_down: &Self::DownT, _id: &Identifier) -> Option<Self::UpT> {
         None
     }
 
     fn fold_fundecl(
         down: &Self::DownT,
-        id: &Identifier,
-        params: &Vec<Identifier>,
-        body: &Box<Stmt>,
+        _id: &Identifier,
+        _params: &[Identifier],
+        body: &Stmt,
     ) -> Option<Self::UpT> {
         Self::fold_stmt(down, body)
     }
 
-    fn fold_assignment(down: &Self::DownT, lhs: &Box<Expr>, rhs: &Box<Expr>) -> Option<Self::UpT> {
+    fn fold_assignment(down: &Self::DownT, lhs: &Expr, rhs: &Expr) -> Option<Self::UpT> {
         let children = [rhs, lhs]
             .iter()
             .map(|e| Self::fold_expr(down, e))
@@ -69,24 +64,19 @@ pub trait Fold {
         Self::reduce(children)
     }
 
-    fn fold_struct(down: &Self::DownT, id: &String, fields: &Vec<Identifier>) -> Option<Self::UpT> {
+    fn fold_struct(_down: &Self::DownT, _id: &str, _fields: &[Identifier]) -> Option<Self::UpT> {
         None
     }
 
-    fn fold_id(down: &Self::DownT, id: &Identifier) -> Option<Self::UpT> {
+    fn fold_id(_down: &Self::DownT, _id: &Identifier) -> Option<Self::UpT> {
         None
     }
 
-    fn fold_literal(down: &Self::DownT, lit: &Literal) -> Option<Self::UpT> {
+    fn fold_literal(_down: &Self::DownT, _lit: &Literal) -> Option<Self::UpT> {
         None
     }
 
-    fn fold_binary(
-        down: &Self::DownT,
-        lhs: &Box<Expr>,
-        op: &Opcode,
-        rhs: &Box<Expr>,
-    ) -> Option<Self::UpT> {
+    fn fold_binary(down: &Self::DownT, lhs: &Expr, _op: &Opcode, rhs: &Expr) -> Option<Self::UpT> {
         let children = [lhs, rhs]
             .iter()
             .map(|e| Self::fold_expr(down, e))
@@ -94,46 +84,63 @@ pub trait Fold {
         Self::reduce(children)
     }
 
-    fn fold_unary(down: &Self::DownT, op: &Opcode, rhs: &Box<Expr>) -> Option<Self::UpT> {
+    fn fold_unary(down: &Self::DownT, _op: &Opcode, rhs: &Expr) -> Option<Self::UpT> {
         Self::fold_expr(down, rhs)
     }
 
-    fn fold_funcall(down: &Self::DownT, id: &String, args: &Vec<Expr>) -> Option<Self::UpT> {
+    fn fold_funcall(down: &Self::DownT, _id: &Identifier, args: &[Expr]) -> Option<Self::UpT> {
         let children = args.iter().map(|e| Self::fold_expr(down, e)).collect();
         Self::reduce(children)
     }
 
-    fn fold_list(down: &Self::DownT, xs: &Vec<Box<Expr>>) -> Option<Self::UpT> {
+    fn fold_list(down: &Self::DownT, xs: &[Box<Expr>]) -> Option<Self::UpT> {
         let children = xs.iter().map(|e| Self::fold_expr(down, e)).collect();
         Self::reduce(children)
     }
 
-    fn fold_dot(down: &Self::DownT, this: &Identifier, action: &Box<Expr>) -> Option<Self::UpT> {
+    fn fold_dot(down: &Self::DownT, _this: &Identifier, action: &Expr) -> Option<Self::UpT> {
         Self::fold_expr(down, action)
     }
 
+    fn fold_index(down: &Self::DownT, xs: &Expr, idx: &Expr) -> Option<Self::UpT> {
+        let children = [xs, idx].iter().map(|e| Self::fold_expr(down, e)).collect();
+        Self::reduce(children)
+    }
+
     fn fold_stmt(down: &Self::DownT, s: &Stmt) -> Option<Self::UpT> {
-        match s {
-            Stmt::Block(body, is_scope) => Self::fold_block(down, body, is_scope),
-            Stmt::If(test, body, alter) => Self::fold_if(down, test, body, alter),
-            Stmt::While(test, body) => Self::fold_while(down, test, body),
-            Stmt::Return(value) => Self::fold_return(down, value),
-            Stmt::Declaration(id) => Self::fold_declaration(down, id),
-            Stmt::FunDecl(id, params, body) => Self::fold_fundecl(down, id, params, body),
-            Stmt::Assignment(lhs, rhs) => Self::fold_assignment(down, lhs, rhs),
-            Stmt::Struct(id, fields) => Self::fold_struct(down, id, fields),
+        Self::walk_stmt(down, s)
+    }
+
+    /// Hands a statement to the hook for its kind, like walk_expr
+    fn walk_stmt(down: &Self::DownT, s: &Stmt) -> Option<Self::UpT> {
+        match &s.kind {
+            StmtKind::Block(body, is_scope) => Self::fold_block(down, body, is_scope),
+            StmtKind::If(test, body, alter) => Self::fold_if(down, test, body, alter),
+            StmtKind::While(test, body) => Self::fold_while(down, test, body),
+            StmtKind::Return(value) => Self::fold_return(down, value),
+            StmtKind::Declaration(id) => Self::fold_declaration(down, id),
+            StmtKind::FunDecl(id, params, body) => Self::fold_fundecl(down, id, params, body),
+            StmtKind::Assignment(lhs, rhs) => Self::fold_assignment(down, lhs, rhs),
+            StmtKind::Struct(id, fields) => Self::fold_struct(down, id, fields),
         }
     }
 
     fn fold_expr(down: &Self::DownT, e: &Expr) -> Option<Self::UpT> {
-        match e {
-            Expr::Id(id) => Self::fold_id(down, id),
-            Expr::Literal(lit) => Self::fold_literal(down, lit),
-            Expr::BinaryOp(lhs, op, rhs) => Self::fold_binary(down, lhs, op, rhs),
-            Expr::UnaryOp(op, rhs) => Self::fold_unary(down, op, rhs),
-            Expr::FunCall(id, args) => Self::fold_funcall(down, id, args),
-            Expr::List(xs) => Self::fold_list(down, xs),
-            Expr::Dot(this, action) => Self::fold_dot(down, this, action),
+        Self::walk_expr(down, e)
+    }
+
+    /// Hands an expression to the hook for its kind. An implementation overriding
+    /// fold_expr calls this to carry on into the hooks.
+    fn walk_expr(down: &Self::DownT, e: &Expr) -> Option<Self::UpT> {
+        match &e.kind {
+            ExprKind::Id(id) => Self::fold_id(down, id),
+            ExprKind::Literal(lit) => Self::fold_literal(down, lit),
+            ExprKind::BinaryOp(lhs, op, rhs) => Self::fold_binary(down, lhs, op, rhs),
+            ExprKind::UnaryOp(op, rhs) => Self::fold_unary(down, op, rhs),
+            ExprKind::FunCall(id, args) => Self::fold_funcall(down, id, args),
+            ExprKind::List(xs) => Self::fold_list(down, xs),
+            ExprKind::Dot(this, action) => Self::fold_dot(down, this, action),
+            ExprKind::Index(xs, idx) => Self::fold_index(down, xs, idx),
         }
     }
 }
@@ -1,4 +1,5 @@
 pub mod fold;
+pub mod rewrite;
 pub mod stack;
 
 use std::collections::hash_map::DefaultHasher;
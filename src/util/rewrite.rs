@@ -0,0 +1,157 @@
+use crate::error::Span;
+use crate::frontend::ast::*;
+
+/// Rewrite Trait
+///
+/// The tree building counterpart to Fold. This trait traverses over an abstract
+/// syntax tree, taking ownership of it, and returns a new one in its place.
+///
+/// By default every node is rebuilt as it was out of its rewritten children, so
+/// the user only supplies functions for the nodes they want to change. A node
+/// keeps its span unless the user chooses otherwise.
+pub trait Rewrite {
+    type DownT; // This value is passed down before the traversal
+
+    fn rewrite_block(down: &Self::DownT, body: Vec<Box<Stmt>>, is_scope: bool, span: Span) -> Stmt {
+        let body = body
+            .into_iter()
+            .map(|s| Box::new(Self::rewrite_stmt(down, *s)))
+            .collect();
+        Stmt::new(StmtKind::Block(body, is_scope), span)
+    }
+
+    fn rewrite_if(down: &Self::DownT, test: Expr, body: Stmt, alter: Stmt, span: Span) -> Stmt {
+        let kind = StmtKind::If(
+            Box::new(Self::rewrite_expr(down, test)),
+            Box::new(Self::rewrite_stmt(down, body)),
+            Box::new(Self::rewrite_stmt(down, alter)),
+        );
+        Stmt::new(kind, span)
+    }
+
+    fn rewrite_while(down: &Self::DownT, test: Expr, body: Stmt, span: Span) -> Stmt {
+        let kind = StmtKind::While(
+            Box::new(Self::rewrite_expr(down, test)),
+            Box::new(Self::rewrite_stmt(down, body)),
+        );
+        Stmt::new(kind, span)
+    }
+
+    fn rewrite_return(down: &Self::DownT, value: Option<Expr>, span: Span) -> Stmt {
+        let value = value.map(|e| Box::new(Self::rewrite_expr(down, e)));
+        Stmt::new(StmtKind::Return(value), span)
+    }
+
+    fn rewrite_declaration(_down: &Self::DownT, id: Identifier, span: Span) -> Stmt {
+        Stmt::new(StmtKind::Declaration(id), span)
+    }
+
+    fn rewrite_fundecl(
+        down: &Self::DownT,
+        id: Identifier,
+        params: Vec<Identifier>,
+        body: Stmt,
+        span: Span,
+    ) -> Stmt {
+        let body = Box::new(Self::rewrite_stmt(down, body));
+        Stmt::new(StmtKind::FunDecl(id, params, body), span)
+    }
+
+    fn rewrite_assignment(down: &Self::DownT, lhs: Expr, rhs: Expr, span: Span) -> Stmt {
+        let kind = StmtKind::Assignment(
+            Box::new(Self::rewrite_expr(down, lhs)),
+            Box::new(Self::rewrite_expr(down, rhs)),
+        );
+        Stmt::new(kind, span)
+    }
+
+    fn rewrite_struct(
+        _down: &Self::DownT,
+        id: String,
+        fields: Vec<Identifier>,
+        span: Span,
+    ) -> Stmt {
+        Stmt::new(StmtKind::Struct(id, fields), span)
+    }
+
+    fn rewrite_id(_down: &Self::DownT, id: Identifier, span: Span) -> Expr {
+        Expr::new(ExprKind::Id(id), span)
+    }
+
+    fn rewrite_literal(_down: &Self::DownT, lit: Literal, span: Span) -> Expr {
+        Expr::new(ExprKind::Literal(lit), span)
+    }
+
+    fn rewrite_binary(down: &Self::DownT, lhs: Expr, op: Opcode, rhs: Expr, span: Span) -> Expr {
+        let kind = ExprKind::BinaryOp(
+            Box::new(Self::rewrite_expr(down, lhs)),
+            op,
+            Box::new(Self::rewrite_expr(down, rhs)),
+        );
+        Expr::new(kind, span)
+    }
+
+    fn rewrite_unary(down: &Self::DownT, op: Opcode, rhs: Expr, span: Span) -> Expr {
+        let kind = ExprKind::UnaryOp(op, Box::new(Self::rewrite_expr(down, rhs)));
+        Expr::new(kind, span)
+    }
+
+    fn rewrite_funcall(down: &Self::DownT, id: Identifier, args: Vec<Expr>, span: Span) -> Expr {
+        let args = args
+            .into_iter()
+            .map(|e| Self::rewrite_expr(down, e))
+            .collect();
+        Expr::new(ExprKind::FunCall(id, args), span)
+    }
+
+    fn rewrite_list(down: &Self::DownT, xs: Vec<Box<Expr>>, span: Span) -> Expr {
+        let xs = xs
+            .into_iter()
+            .map(|e| Box::new(Self::rewrite_expr(down, *e)))
+            .collect();
+        Expr::new(ExprKind::List(xs), span)
+    }
+
+    fn rewrite_dot(down: &Self::DownT, this: Identifier, action: Expr, span: Span) -> Expr {
+        let action = Box::new(Self::rewrite_expr(down, action));
+        Expr::new(ExprKind::Dot(this, action), span)
+    }
+
+    fn rewrite_index(down: &Self::DownT, xs: Expr, idx: Expr, span: Span) -> Expr {
+        let kind = ExprKind::Index(
+            Box::new(Self::rewrite_expr(down, xs)),
+            Box::new(Self::rewrite_expr(down, idx)),
+        );
+        Expr::new(kind, span)
+    }
+
+    fn rewrite_stmt(down: &Self::DownT, s: Stmt) -> Stmt {
+        let span = s.span;
+        match s.kind {
+            StmtKind::Block(body, is_scope) => Self::rewrite_block(down, body, is_scope, span),
+            StmtKind::If(test, body, alter) => Self::rewrite_if(down, *test, *body, *alter, span),
+            StmtKind::While(test, body) => Self::rewrite_while(down, *test, *body, span),
+            StmtKind::Return(value) => Self::rewrite_return(down, value.map(|e| *e), span),
+            StmtKind::Declaration(id) => Self::rewrite_declaration(down, id, span),
+            StmtKind::FunDecl(id, params, body) => {
+                Self::rewrite_fundecl(down, id, params, *body, span)
+            }
+            StmtKind::Assignment(lhs, rhs) => Self::rewrite_assignment(down, *lhs, *rhs, span),
+            StmtKind::Struct(id, fields) => Self::rewrite_struct(down, id, fields, span),
+        }
+    }
+
+    fn rewrite_expr(down: &Self::DownT, e: Expr) -> Expr {
+        let span = e.span;
+        match e.kind {
+            ExprKind::Id(id) => Self::rewrite_id(down, id, span),
+            ExprKind::Literal(lit) => Self::rewrite_literal(down, lit, span),
+            ExprKind::BinaryOp(lhs, op, rhs) => Self::rewrite_binary(down, *lhs, op, *rhs, span),
+            ExprKind::UnaryOp(op, rhs) => Self::rewrite_unary(down, op, *rhs, span),
+            ExprKind::FunCall(id, args) => Self::rewrite_funcall(down, id, args, span),
+            ExprKind::List(xs) => Self::rewrite_list(down, xs, span),
+            ExprKind::Dot(this, action) => Self::rewrite_dot(down, this, *action, span),
+            ExprKind::Index(xs, idx) => Self::rewrite_index(down, *xs, *idx, span),
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use crate::vm::{EstaData, EstaType};
+use crate::error::Span;
+use crate::vm::EstaData;
 use std::collections::HashMap;
 use std::convert::From;
 use strum::IntoEnumIterator;
@@ -9,14 +10,43 @@ pub enum ByteCode {
     JUMP,   // Set PC to argument
     JUMPF,  // Pops top, if top == False, then set PC to argument
     POP,    // Pops off the top item on the stack
+    DUP,    // Pushes a copy of the top item on the stack
     ADD,    // Pops off the top two items from the stack, tries to add and push a result
+    SUB,    // Pops off the top two items from the stack, tries to subtract and push a result
+    MUL,    // Pops off the top two items from the stack, tries to multiply and push a result
+    DIV,    // Pops off the top two items from the stack, tries to divide and push a result
+    MOD,    // Pops off the top two items from the stack, pushes the remainder of lhs / rhs
+    GT,     // Pops off the top two items from the stack, pushes whether lhs > rhs
+    GE,     // Pops off the top two items from the stack, pushes whether lhs >= rhs
+    LT,     // Pops off the top two items from the stack, pushes whether lhs < rhs
+    LE,     // Pops off the top two items from the stack, pushes whether lhs <= rhs
+    EQ,     // Pops off the top two items from the stack, pushes whether lhs == rhs
+    NE,     // Pops off the top two items from the stack, pushes whether lhs != rhs
+    NOT,    // Pops off the top item from the stack, pushes its logical negation
+    NEG,    // Pops off the top item from the stack, pushes its arithmetic negation
+    LIST,   // Pops off N items from the stack, pushes a new list holding them
+    LOADI,  // Pops an index and a list, pushes the list's element at that index
+    STOREI, // Pops an index and a list, stores the top of stack to that index of the list
     LOADC,  // Loads an EstaData from the constant's pool for that function and pushes to stack
+    LOADCW, // A LOADC whose index is too wide for one argument, given as upper and lower halves
     LOADV,  // Loads an EstaData variable from the environment's pool and pushes to stack
     STOREV, // Stores the top of stack to the environment's pool
+    LOADG,  // Loads a global variable from the outermost environment frame and pushes to stack
+    STOREG, // Stores the top of stack to a global variable in the outermost environment frame
+    POPV,   // Pops the top of stack into the environment's pool, a STOREV followed by a POP
+    POPG,   // Pops the top of stack into a global variable, a STOREG followed by a POP
     PUSHE,  // Pushes a new environment frame, one argument is the number of local variables
     POPE,   // Pops the first environment frame
     PUSHS,  // Push a new stack frame
     POPS,   // Pop a new stack frame
+    CALL,   // Moves N arguments into a new stack frame and jumps to the function address
+    CALLN,  // Pops N arguments, calls the imported native function and pushes its result
+    CALLM,  // Pops N arguments and a host object, calls the named method and pushes its result
+    RET,    // Pops the return value, unwinds the current call frame and returns to the caller
+    NEW,    // Allocates a struct with the given tag and size on the heap and pushes its address
+    LOADF,  // Pops a struct address and pushes the value of the named field
+    STOREF, // Pops a struct address and stores the top of stack to the named field
+    CHECK,  // Errors unless the top of stack has the given kind (and struct tag, for structs)
 }
 
 impl From<u8> for ByteCode {
@@ -36,45 +66,76 @@ impl From<ByteCode> for u8 {
 }
 
 lazy_static! {
-    pub static ref BYTECODE_ARRAY: Vec<ByteCode> = { ByteCode::iter().collect() };
+    pub static ref BYTECODE_ARRAY: Vec<ByteCode> = ByteCode::iter().collect();
     pub static ref BYTECODE_ARITY: HashMap<ByteCode, u32> = {
         let mut m = HashMap::new();
         m.insert(ByteCode::HALT, 0);
         m.insert(ByteCode::JUMP, 1);
         m.insert(ByteCode::JUMPF, 1);
         m.insert(ByteCode::POP, 0);
+        m.insert(ByteCode::DUP, 0);
         m.insert(ByteCode::ADD, 0);
+        m.insert(ByteCode::SUB, 0);
+        m.insert(ByteCode::MUL, 0);
+        m.insert(ByteCode::DIV, 0);
+        m.insert(ByteCode::MOD, 0);
+        m.insert(ByteCode::GT, 0);
+        m.insert(ByteCode::GE, 0);
+        m.insert(ByteCode::LT, 0);
+        m.insert(ByteCode::LE, 0);
+        m.insert(ByteCode::EQ, 0);
+        m.insert(ByteCode::NE, 0);
+        m.insert(ByteCode::NOT, 0);
+        m.insert(ByteCode::NEG, 0);
+        m.insert(ByteCode::LIST, 1);
+        m.insert(ByteCode::LOADI, 0);
+        m.insert(ByteCode::STOREI, 0);
         m.insert(ByteCode::LOADC, 1);
+        m.insert(ByteCode::LOADCW, 2);
         m.insert(ByteCode::LOADV, 2);
         m.insert(ByteCode::STOREV, 2);
+        m.insert(ByteCode::LOADG, 1);
+        m.insert(ByteCode::STOREG, 1);
+        m.insert(ByteCode::POPV, 2);
+        m.insert(ByteCode::POPG, 1);
         m.insert(ByteCode::PUSHE, 1);
         m.insert(ByteCode::POPE, 0);
         m.insert(ByteCode::PUSHS, 0);
         m.insert(ByteCode::POPS, 0);
+        m.insert(ByteCode::CALL, 2);
+        m.insert(ByteCode::CALLN, 2);
+        m.insert(ByteCode::CALLM, 2);
+        m.insert(ByteCode::RET, 0);
+        m.insert(ByteCode::NEW, 2);
+        m.insert(ByteCode::LOADF, 1);
+        m.insert(ByteCode::STOREF, 1);
+        m.insert(ByteCode::CHECK, 2);
         m
     };
 }
 
 /// Metainsts are an intermediate representation of bytecode that will be further
 /// simplified by the compiler at a later stage
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MetaInst {
     ByteCode(ByteCode),
     Number(i16),
+    Operand(usize), // A count, index or tag, which must fit in an argument once assembled
     Label(String),
     Const(EstaData),
-    Identifier(String),
-    Declaration(String),
+    Field(String),
+    Native(String),
+    Span(Span), // The instructions from here on were compiled from the source at this span
 }
 
-pub fn disassemble_u8(v: &Vec<u8>) -> Vec<MetaInst> {
+pub fn disassemble_u8(v: &[u8]) -> Vec<MetaInst> {
     let mut idx = 0;
     let mut inst = Vec::new();
     while idx < v.len() {
         let bytecode = ByteCode::from(v[idx]);
         idx += 1;
         inst.push(MetaInst::ByteCode(bytecode));
-        for _ in 0..(BYTECODE_ARITY.get(&bytecode).unwrap().clone()) {
+        for _ in 0..BYTECODE_ARITY[&bytecode] {
             let upper = v[idx];
             idx += 1;
             let lower = v[idx];
@@ -0,0 +1,255 @@
+use crate::error::MEMORY_LIMIT;
+use crate::vm::EstaData;
+use std::mem::size_of;
+
+/// Every value is counted as one cell of this size, plus the text of a string
+pub const CELL_SIZE: usize = size_of::<EstaData>();
+
+/// Bytes that may be allocated before the first collection
+const FIRST_COLLECTION: usize = 1 << 20;
+
+/// # The Esta Heap
+///
+/// Values that outlive the expression that created them, such as struct
+/// instances, are allocated on the heap. Everything else refers to them by
+/// their address, which is simply their index into the heap.
+///
+/// ## Accounting
+/// The heap keeps count of the bytes the VM has allocated, which includes the
+/// VM's frames and operand stack as well as the objects on the heap, so that
+/// natives growing a list are held to the same limit as the VM itself. Values are
+/// counted by the cell, plus the text of a string, wherever they are held.
+/// Strings are copied rather than shared, so every copy counts.
+///
+/// ## Collection
+/// Objects the program can no longer reach are reclaimed by a mark and sweep
+/// collection, which the VM runs between instructions once the bytes in use
+/// have doubled since the last one, or have gone halfway to the limit. Their
+/// addresses are handed out again to later objects, so natives and host objects
+/// must not hold on to a struct or list past the call they were given it in.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Option<HeapObject>>, // None where an object was reclaimed
+    free: Vec<usize>,                 // Addresses of reclaimed objects, to be reused
+    used: usize,                      // Bytes allocated so far
+    limit: Option<usize>,             // Bytes that may be allocated at most, if limited
+    next_collection: usize,           // Bytes in use past which to collect next
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free: Vec::new(),
+            used: 0,
+            limit: None,
+            next_collection: FIRST_COLLECTION,
+        }
+    }
+}
+
+/// A struct instance is laid out exactly as its EstaStruct describes, the first
+/// cell holds the struct's tag, the second its size and the rest its fields.
+/// A list simply holds its elements.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapObject {
+    Struct(Vec<EstaData>),
+    List(Vec<EstaData>),
+}
+
+impl HeapObject {
+    pub fn size(&self) -> usize {
+        let cells = match self {
+            HeapObject::Struct(cells) => cells,
+            HeapObject::List(xs) => xs,
+        };
+        size_of::<HeapObject>() + cells.iter().map(EstaData::size).sum::<usize>()
+    }
+
+    fn cells(&self) -> &[EstaData] {
+        match self {
+            HeapObject::Struct(cells) => cells,
+            HeapObject::List(xs) => xs,
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Default::default()
+    }
+
+    pub fn alloc(&mut self, object: HeapObject) -> Result<usize, &'static str> {
+        self.reserve(object.size())?;
+        match self.free.pop() {
+            Some(addr) => {
+                self.objects[addr] = Some(object);
+                Ok(addr)
+            }
+            None => {
+                self.objects.push(Some(object));
+                Ok(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Whether enough has been allocated since the last collection to run another
+    pub fn wants_collection(&self) -> bool {
+        self.used > self.next_collection
+    }
+
+    /// Reclaims every object that can't be reached from roots or the pinned
+    /// addresses, following the cells of every object that can
+    pub fn collect<'a, R, P>(&mut self, roots: R, pinned: P)
+    where
+        R: Iterator<Item = &'a EstaData>,
+        P: Iterator<Item = usize>,
+    {
+        let mut marked = vec![false; self.objects.len()];
+        let mut work: Vec<usize> = roots.filter_map(EstaData::address).chain(pinned).collect();
+        while let Some(addr) = work.pop() {
+            if addr >= marked.len() || marked[addr] {
+                continue;
+            }
+            marked[addr] = true;
+            if let Some(object) = &self.objects[addr] {
+                work.extend(object.cells().iter().filter_map(EstaData::address));
+            }
+        }
+
+        let mut freed = 0;
+        for (addr, slot) in self.objects.iter_mut().enumerate() {
+            if marked[addr] {
+                continue;
+            }
+            if let Some(object) = slot.take() {
+                freed += object.size();
+                self.free.push(addr);
+            }
+        }
+        self.release(freed);
+        self.schedule();
+    }
+
+    // The next collection waits until the bytes in use have doubled, but comes
+    // early enough to leave room under the limit
+    fn schedule(&mut self) {
+        let mut next = self.used.saturating_mul(2).max(FIRST_COLLECTION);
+        if let Some(limit) = self.limit {
+            next = next.min(self.used + limit.saturating_sub(self.used) / 2);
+        }
+        self.next_collection = next;
+    }
+
+    /// Counts bytes as allocated, unless that would go over the limit
+    pub fn reserve(&mut self, bytes: usize) -> Result<(), &'static str> {
+        let used = self.used.saturating_add(bytes);
+        if self.limit.is_some_and(|limit| used > limit) {
+            return Err(MEMORY_LIMIT);
+        }
+        self.used = used;
+        Ok(())
+    }
+
+    /// Counts bytes as freed again
+    pub fn release(&mut self, bytes: usize) {
+        self.used = self.used.saturating_sub(bytes);
+    }
+
+    /// Counts new bytes in place of old ones, which only fails if there are more
+    pub fn replace(&mut self, old: usize, new: usize) -> Result<(), &'static str> {
+        if new > old {
+            self.reserve(new - old)
+        } else {
+            self.release(old - new);
+            Ok(())
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        self.schedule();
+    }
+
+    pub fn get(&self, addr: usize) -> Result<&HeapObject, &'static str> {
+        let object = self.objects.get(addr).and_then(Option::as_ref);
+        object.ok_or("Invalid heap address")
+    }
+
+    pub fn get_mut(&mut self, addr: usize) -> Result<&mut HeapObject, &'static str> {
+        let object = self.objects.get_mut(addr).and_then(Option::as_mut);
+        object.ok_or("Invalid heap address")
+    }
+
+    pub fn get_struct(&self, addr: usize) -> Result<&Vec<EstaData>, &'static str> {
+        match self.get(addr)? {
+            HeapObject::Struct(cells) => Ok(cells),
+            _ => Err("Heap object is not a struct"),
+        }
+    }
+
+    pub fn get_struct_mut(&mut self, addr: usize) -> Result<&mut Vec<EstaData>, &'static str> {
+        match self.get_mut(addr)? {
+            HeapObject::Struct(cells) => Ok(cells),
+            _ => Err("Heap object is not a struct"),
+        }
+    }
+
+    pub fn get_list(&self, addr: usize) -> Result<&Vec<EstaData>, &'static str> {
+        match self.get(addr)? {
+            HeapObject::List(xs) => Ok(xs),
+            _ => Err("Heap object is not a list"),
+        }
+    }
+
+    pub fn get_list_mut(&mut self, addr: usize) -> Result<&mut Vec<EstaData>, &'static str> {
+        match self.get_mut(addr)? {
+            HeapObject::List(xs) => Ok(xs),
+            _ => Err("Heap object is not a list"),
+        }
+    }
+
+    /// Stores data into a cell of the struct or list at addr, counting it in place
+    /// of the value it overwrites
+    pub fn store(&mut self, addr: usize, idx: usize, data: EstaData) -> Result<(), &'static str> {
+        let cells = match self.get_mut(addr)? {
+            HeapObject::Struct(cells) => cells,
+            HeapObject::List(xs) => xs,
+        };
+        let old = cells.get(idx).ok_or("Index out of range")?.size();
+        self.replace(old, data.size())?;
+        match self.get_mut(addr)? {
+            HeapObject::Struct(cells) => cells[idx] = data,
+            HeapObject::List(xs) => xs[idx] = data,
+        }
+        Ok(())
+    }
+
+    pub fn push_list(&mut self, addr: usize, data: EstaData) -> Result<(), &'static str> {
+        self.get_list(addr)?;
+        self.reserve(data.size())?;
+        self.get_list_mut(addr)?.push(data);
+        Ok(())
+    }
+
+    pub fn pop_list(&mut self, addr: usize) -> Result<Option<EstaData>, &'static str> {
+        let data = self.get_list_mut(addr)?.pop();
+        if let Some(data) = &data {
+            self.release(data.size());
+        }
+        Ok(data)
+    }
+
+    /// The number of objects on the heap, not counting reclaimed ones
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -1,9 +1,22 @@
 use crate::backend::program::*;
+use crate::error::{ErrorKind, EstaError, Span, STACK_OVERFLOW};
 use crate::vm::bytecode::*;
-use std::collections::HashMap;
+use crate::vm::heap::*;
+use crate::vm::native::*;
+use crate::vm::object::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use strum::IntoEnumIterator;
 
 pub mod bytecode;
+pub mod heap;
+pub mod native;
+pub mod object;
 #[cfg(test)]
 mod tests;
 
@@ -32,135 +45,753 @@ mod tests;
 /// ## Stack Field
 /// The stack section is a stack of EstaData, which is used to hold intermediate
 /// values during computations.
+///
+/// ## Heap Field
+/// The heap holds every struct instance and list. Struct and list values on the
+/// stack and in the env are just addresses into the heap, so assigning one shares it.
+/// Once nothing refers to an object any more it is reclaimed, see Heap, except
+/// that those handed out to the host through run(), call() or global() are kept.
+///
+/// ## Layouts Field
+/// Fields are accessed by name, as variables don't know which struct they hold
+/// ahead of time. This LUT maps a struct's tag and a field name to the field's
+/// offset within the struct.
+///
+/// ## Natives Field
+/// Calls to Rust functions are compiled into imports by name. The natives
+/// registry starts out with every builtin and embedders may register more with
+/// register_native() before running the program. Imports are linked to the ids
+/// of their natives once, before the VM runs, so a call doesn't look up a name
+/// and a native that was never registered is reported before anything runs.
+///
+/// ## Frames Field
+/// Every function invocation pushes a call frame, which remembers where to return
+/// to and how deep the env and stack were when the function was called. Returning
+/// unwinds both back to those depths, no matter how many scopes were left open.
+///
+/// ## Fuel and Cancellation
+/// Every instruction but HALT costs one unit of fuel. A VM given a limited amount
+/// with set_fuel() stops with an OutOfFuel error once it runs out, so a script
+/// stuck in a loop can't run forever. A CancelHandle stops the VM from another
+/// thread with a Cancelled error instead. Either way the VM stops before the next
+/// instruction, and calls made through call(), as well as the top level
+/// statements and main, are unwound as for any other error.
+///
+/// ## Limits
+/// The heap counts the bytes taken up by env frames, call frames, the values on
+/// the stack and heap objects, strings along with their text. Once a memory limit
+/// is set with set_limits(), an allocation that would take the bytes in use at
+/// once over it fails with a MemoryLimit error. Calls nested deeper than the call depth limit, 10,000
+/// unless set otherwise, fail with a StackOverflow error instead, which catches
+/// runaway recursion early on.
 #[derive(Debug)]
 pub struct VirtualMachine {
-    insts: Vec<u8>,            // An array of bytecode instructions
-    stack: Vec<Vec<EstaData>>, // A stack of frames, one for each function
-    env: Vec<Vec<EstaData>>,   // A stack of variable bindings, one for each scope
-    consts: Vec<EstaData>,     // All constants used in the program
-    context: String,           // The current executing function. Used to lookup consts
-    pc: usize,                 // Program counter. Indexes current instruction
+    insts: Vec<u8>,                           // An array of bytecode instructions
+    stack: Vec<Vec<EstaData>>,                // A stack of frames, one for each function
+    env: Vec<Vec<EstaData>>,                  // A stack of variable bindings, one for each scope
+    consts: Vec<EstaData>,                    // All constants used in the program
+    frames: Vec<CallFrame>,                   // A stack of call frames, one for each function
+    heap: Heap,                               // All heap allocated objects
+    pinned: RefCell<HashSet<usize>>,          // Addresses handed out to the host
+    layouts: HashMap<(usize, usize), usize>,  // Offset of a field, by struct tag and field
+    fields: Vec<String>,                      // Names of every field and method accessed
+    structs: Vec<String>,                     // Struct names, by tag
+    functions: HashMap<usize, String>,        // Function names, by entry point
+    entries: HashMap<String, (usize, usize)>, // Function entry points and arities, by name
+    globals: HashMap<String, usize>,          // Index of every global variable, by name
+    natives: Natives,                         // Every native function the program may call
+    imports: Vec<String>,                     // Names of the natives the program calls
+    links: Vec<usize>,                        // Id of the native for every import, once linked
+    fuel: Option<usize>,                      // Instructions left to run, if limited
+    max_depth: Option<usize>,                 // Most calls that may be in progress, if limited
+    cancelled: Arc<AtomicBool>,               // Set by a CancelHandle to stop the VM
+    lines: Vec<(usize, Span)>,                // Source span of the code from each offset on
+    context: String, // The current executing function. Used to lookup consts
+    pc: usize,       // Program counter. Indexes current instruction
+}
+
+#[derive(Debug, Clone)]
+struct CallFrame {
+    ret_pc: usize,    // Where to continue in the caller
+    env_len: usize,   // Depth of the env when the function was called
+    stack_len: usize, // Depth of the stack when the function was called
+    context: String,  // The caller's context
+    bytes: usize,     // Bytes counted for the call frame itself
+}
+
+/// Caps on the resources a program may use, see VirtualMachine::set_limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub memory: Option<usize>,     // Bytes the VM may have in use at once
+    pub call_depth: Option<usize>, // Calls that may be in progress at once
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            memory: None,
+            call_depth: Some(10_000),
+        }
+    }
 }
 
 impl VirtualMachine {
     pub fn new(prog: Program) -> VirtualMachine {
         assert!(!prog.insts.is_empty());
         let stack = vec![Vec::new()];
-        let env = vec![Vec::new()];
+        let env = vec![vec![EstaData::default(); prog.globals.len()]];
+        let mut heap = Heap::new();
+        heap.reserve(VirtualMachine::env_size(&env[0])).unwrap();
+        let globals = prog
+            .globals
+            .into_iter()
+            .enumerate()
+            .map(|(idx, name)| (name, idx))
+            .collect();
+        let functions = prog
+            .functions
+            .iter()
+            .map(|(name, (offset, _))| (*offset, name.clone()))
+            .collect();
+        let mut structs = vec![String::new(); prog.structs.len()];
+        let mut layouts = HashMap::new();
+        for s in prog.structs.iter() {
+            structs[s.tag] = s.id.clone();
+            for (idx, field) in prog.fields.iter().enumerate() {
+                if let Some(offset) = s.fields.get(field) {
+                    layouts.insert((s.tag, idx), *offset);
+                }
+            }
+        }
         VirtualMachine {
             insts: prog.insts,
             stack,
             env,
             consts: prog.consts,
+            frames: Vec::new(),
+            heap,
+            pinned: Default::default(),
+            layouts,
+            fields: prog.fields,
+            structs,
+            functions,
+            entries: prog.functions,
+            globals,
+            natives: Natives::new(),
+            imports: prog.natives,
+            links: Vec::new(),
+            lines: prog.lines,
+            fuel: None,
+            max_depth: Limits::default().call_depth,
+            cancelled: Arc::new(AtomicBool::new(false)),
             context: "GLOBAL".to_string(),
             pc: 0,
         }
     }
 
-    pub fn run(&mut self) -> Result<(), &'static str> {
+    /// Makes a Rust function callable from Esta under name. Registering a name
+    /// that is already taken, including a builtin's, replaces that native.
+    /// If arity is None, the native accepts any number of arguments.
+    pub fn register_native<F>(&mut self, name: &str, arity: Option<usize>, func: F)
+    where
+        F: FnMut(&mut Heap, Vec<EstaData>) -> Result<EstaData, EstaError> + 'static,
+    {
+        self.natives.register(name, arity, func);
+        self.links.clear();
+    }
+
+    /// Runs the top level statements of the program and then main, if there is one.
+    /// The result is main's return value, which is popped off the stack, or else
+    /// whatever the program left on top of the stack, or Nil if it left nothing.
+    pub fn run(&mut self) -> Result<EstaData, EstaError> {
         debug!("{}", self);
         debug!("Inst: {:?}", disassemble_u8(&self.insts));
         debug!("Raw Inst: {:?}", &self.insts);
         debug!("Consts: {:?}", self.consts);
 
+        self.prelude()?;
+        if self.entries.contains_key("main") {
+            let result = self.unwinding(|vm| {
+                vm.enter("main", Vec::new())?;
+                vm.resume()?;
+                Ok(vm.pop_top()?)
+            });
+            return result.map(|data| self.pin(data));
+        }
+        let top = self.stack.last().and_then(|frame| frame.last());
+        Ok(self.pin(top.cloned().unwrap_or_default()))
+    }
+
+    /// Calls the named function with args and returns its result. The top level
+    /// statements are run first if they haven't been yet. If the function fails,
+    /// everything it left behind is unwound so the VM can still be used.
+    pub fn call(&mut self, name: &str, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+        self.prelude()?;
+        let result = self.unwinding(|vm| {
+            vm.enter(name, args)?;
+            vm.resume()?;
+            Ok(vm.pop_top()?)
+        });
+        result.map(|data| self.pin(data))
+    }
+
+    // A struct or list handed out to the host may be handed back at any time, so
+    // it is never reclaimed
+    fn pin(&self, data: EstaData) -> EstaData {
+        if let Some(addr) = data.address() {
+            self.pinned.borrow_mut().insert(addr);
+        }
+        data
+    }
+
+    /// Reclaims every struct and list the program can no longer reach, which the
+    /// VM also does on its own as it allocates. Only ever done between
+    /// instructions, when every value in use is on the stack or in the env.
+    pub fn collect_garbage(&mut self) {
+        let roots = self.stack.iter().chain(self.env.iter()).flatten();
+        let pinned = self.pinned.borrow();
+        self.heap
+            .collect(roots.chain(self.consts.iter()), pinned.iter().copied());
+    }
+
+    // Runs the top level statements, unless they have already run to the HALT.
+    // Should they fail, they are unwound back to the start, so that the next call
+    // runs them again, once the embedder had a chance to set up what was missing.
+    fn prelude(&mut self) -> Result<(), EstaError> {
+        if self.halted() {
+            return Ok(());
+        }
+        self.unwinding(|vm| {
+            vm.resume()?;
+            Ok(EstaData::default())
+        })?;
+        Ok(())
+    }
+
+    // Runs a call from a halted VM. Should it fail, every frame, scope and value it
+    // left behind is dropped and the VM is halted where it was before the call.
+    fn unwinding<F>(&mut self, call: F) -> Result<EstaData, EstaError>
+    where
+        F: FnOnce(&mut Self) -> Result<EstaData, EstaError>,
+    {
+        let (pc, env_len, stack_len) = (self.pc, self.env.len(), self.stack.len());
+        let (frames_len, top_len) = (self.frames.len(), self.stack[stack_len - 1].len());
+        let context = self.context.clone();
+        let result = call(self);
+        if result.is_err() {
+            for frame in self.frames.split_off(frames_len) {
+                self.heap.release(frame.bytes);
+            }
+            self.truncate_env(env_len);
+            self.truncate_stack(stack_len);
+            let dropped = self.stack[stack_len - 1].split_off(top_len);
+            self.heap.release(VirtualMachine::cells_size(&dropped));
+            self.context = context;
+            self.pc = pc;
+        }
+        result
+    }
+
+    /// Sets up a call to the named function from a halted VM. Stepping the VM runs
+    /// the function, and once it returns the VM halts again with the result on
+    /// top of the stack.
+    pub fn enter(&mut self, name: &str, args: Vec<EstaData>) -> Result<(), EstaError> {
+        if !self.halted() {
+            return Err(EstaError::runtime(
+                "Functions can only be called once halted",
+            ));
+        }
+        let (addr, arity) = *self
+            .entries
+            .get(name)
+            .ok_or_else(|| EstaError::runtime(format!("Unknown function {}", name)))?;
+        if args.len() != arity {
+            return Err(EstaError::runtime(format!(
+                "{} takes {} argument{} but {} {} given",
+                name,
+                arity,
+                if arity == 1 { "" } else { "s" },
+                args.len(),
+                if args.len() == 1 { "was" } else { "were" }
+            )));
+        }
+        self.link()?;
+        Ok(self.invoke(addr, args)?)
+    }
+
+    /// Sets a global variable by name
+    pub fn set_global(&mut self, name: &str, value: EstaData) -> Result<(), EstaError> {
+        let idx = *self
+            .globals
+            .get(name)
+            .ok_or_else(|| EstaError::runtime(format!("Unknown global {}", name)))?;
+        Ok(self.store(0, idx, value)?)
+    }
+
+    /// Gets a global variable by name
+    pub fn global(&self, name: &str) -> Option<EstaData> {
+        let idx = *self.globals.get(name)?;
+        Some(self.pin(self.env.first()?.get(idx).cloned()?))
+    }
+
+    /// Limits how many more instructions the VM may run, or lifts the limit with None
+    pub fn set_fuel(&mut self, fuel: Option<usize>) {
+        self.fuel = fuel;
+    }
+
+    /// How many more instructions the VM may run, if limited
+    pub fn fuel(&self) -> Option<usize> {
+        self.fuel
+    }
+
+    /// Creates a handle that stops this VM when cancelled. A cancelled VM stays
+    /// cancelled, so every later call into it fails as well.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    /// Sets the limits on memory and call depth, which apply from the next
+    /// allocation or call on
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.set_limit(limits.memory);
+        self.max_depth = limits.call_depth;
+    }
+
+    /// Bytes currently allocated by the VM, see Heap
+    pub fn memory_used(&self) -> usize {
+        self.heap.used()
+    }
+
+    // Registering a native may change what the imports link to, so the links
+    // are made again after every registration
+    fn link(&mut self) -> Result<(), EstaError> {
+        if self.links.len() == self.imports.len() {
+            return Ok(());
+        }
+        self.links = self
+            .imports
+            .iter()
+            .map(|name| {
+                self.natives.lookup(name).ok_or_else(|| {
+                    EstaError::runtime(format!("Native function {} is not registered", name))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), EstaError> {
+        self.link()?;
         while VMStatus::RUNNING == self.step()? {
             debug!("{}", self);
-            debug!(
-                "Inst: {:?}",
-                disassemble_u8(&self.insts[self.pc..].to_vec())
-            );
+            debug!("Inst: {:?}", disassemble_u8(&self.insts[self.pc..]));
         }
         Ok(())
     }
 
-    pub fn step(&mut self) -> Result<VMStatus, &'static str> {
-        let inst = BYTECODE_ARRAY[self.insts[self.pc] as usize].clone();
+    fn burn_fuel(&mut self) -> Result<(), EstaError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(EstaError::new(
+                ErrorKind::Cancelled,
+                "The program was cancelled",
+            ));
+        }
+        match &mut self.fuel {
+            Some(0) => Err(EstaError::new(
+                ErrorKind::OutOfFuel,
+                "The program ran out of fuel",
+            )),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // A halted VM's program counter stays on the HALT
+    fn halted(&self) -> bool {
+        BYTECODE_ARRAY[self.insts[self.pc] as usize] == ByteCode::HALT
+    }
+
+    /// Runs a single instruction. An error is reported at the span of the source
+    /// that the failing instruction was compiled from.
+    pub fn step(&mut self) -> Result<VMStatus, EstaError> {
+        let pc = self.pc;
+        self.execute()
+            .map_err(|error| match (error.span, self.span_at(pc)) {
+                (None, Some(span)) => error.with_span(span),
+                _ => error,
+            })
+    }
+
+    fn span_at(&self, pc: usize) -> Option<Span> {
+        let idx = self.lines.partition_point(|(offset, _)| *offset <= pc);
+        self.lines[..idx].last().map(|(_, span)| *span)
+    }
+
+    fn execute(&mut self) -> Result<VMStatus, EstaError> {
+        let inst = BYTECODE_ARRAY[self.insts[self.pc] as usize];
+        if inst != ByteCode::HALT {
+            self.burn_fuel()?;
+        }
+        if self.heap.wants_collection() {
+            self.collect_garbage();
+        }
         self.pc += 1;
 
         match inst {
             ByteCode::POP => {
                 self.pop_top()?;
             }
+            ByteCode::DUP => {
+                let data = self.peek_top()?;
+                self.push_top(data)?;
+            }
             ByteCode::HALT => {
+                self.pc -= 1;
                 return Ok(VMStatus::HALTED);
             }
             ByteCode::JUMP => {
-                self.pc = self.read_inst_i16() as usize;
+                self.pc = self.read_inst_u16() as usize;
             }
             ByteCode::JUMPF => {
-                let pc = self.read_inst_i16() as usize;
+                let pc = self.read_inst_u16() as usize;
                 if !self.pop_top()?.eval_bool()? {
                     self.pc = pc
                 }
             }
             ByteCode::LOADV => {
-                let offset = self.env.len() - 1 - self.read_inst_i16() as usize;
-                let idx = self.read_inst_i16() as usize;
+                let offset = self.env.len() - 1 - self.read_inst_u16() as usize;
+                let idx = self.read_inst_u16() as usize;
                 let data = self.env[offset][idx].clone();
-                self.push_top(data);
+                self.push_top(data)?;
             }
             ByteCode::STOREV => {
-                let offset = self.env.len() - 1 - self.read_inst_i16() as usize;
-                let idx = self.read_inst_i16() as usize;
+                let offset = self.env.len() - 1 - self.read_inst_u16() as usize;
+                let idx = self.read_inst_u16() as usize;
+                let data = self.peek_top()?;
+                self.store(offset, idx, data)?;
+            }
+            ByteCode::LOADG => {
+                let idx = self.read_inst_u16() as usize;
+                let data = self.globals()?[idx].clone();
+                self.push_top(data)?;
+            }
+            ByteCode::STOREG => {
+                let idx = self.read_inst_u16() as usize;
                 let data = self.peek_top()?;
-                self.env[offset][idx] = data;
+                self.store(0, idx, data)?;
+            }
+            ByteCode::POPV => {
+                let offset = self.env.len() - 1 - self.read_inst_u16() as usize;
+                let idx = self.read_inst_u16() as usize;
+                let data = self.pop_top()?;
+                self.store(offset, idx, data)?;
+            }
+            ByteCode::POPG => {
+                let idx = self.read_inst_u16() as usize;
+                let data = self.pop_top()?;
+                self.store(0, idx, data)?;
             }
             ByteCode::LOADC => {
-                let idx = self.read_inst_i16() as usize;
-                self.push_top(self.consts[idx].clone())
+                let idx = self.read_inst_u16() as usize;
+                self.push_top(self.consts[idx].clone())?;
             }
-            ByteCode::ADD => {
-                let rhs = self.pop_top()?;
-                let lhs = self.pop_top()?;
-                let result = EstaData::new_add(lhs, rhs)?;
-                self.push_top(result);
+            ByteCode::LOADCW => {
+                let upper = self.read_inst_u16() as usize;
+                let lower = self.read_inst_u16() as usize;
+                self.push_top(self.consts[upper << 16 | lower].clone())?;
+            }
+            ByteCode::ADD => self.binary_op(EstaData::new_add)?,
+            ByteCode::SUB => self.binary_op(EstaData::new_sub)?,
+            ByteCode::MUL => self.binary_op(EstaData::new_mul)?,
+            ByteCode::DIV => self.binary_op(EstaData::new_div)?,
+            ByteCode::MOD => self.binary_op(EstaData::new_mod)?,
+            ByteCode::GT => self.binary_op(EstaData::new_gt)?,
+            ByteCode::GE => self.binary_op(EstaData::new_ge)?,
+            ByteCode::LT => self.binary_op(EstaData::new_lt)?,
+            ByteCode::LE => self.binary_op(EstaData::new_le)?,
+            ByteCode::EQ => self.binary_op(EstaData::new_eq)?,
+            ByteCode::NE => self.binary_op(EstaData::new_ne)?,
+            ByteCode::NOT => self.unary_op(EstaData::new_not)?,
+            ByteCode::NEG => self.unary_op(EstaData::new_neg)?,
+            ByteCode::LIST => {
+                let count = self.read_inst_u16() as usize;
+                let top = self.stack.len() - 1;
+                if self.stack[top].len() < count {
+                    return Err("Not enough elements on the stack".into());
+                }
+                let split = self.stack[top].len() - count;
+                let xs = self.stack[top].split_off(split);
+                self.heap.release(VirtualMachine::cells_size(&xs));
+                let addr = self.heap.alloc(HeapObject::List(xs))?;
+                self.push_top(EstaData::new_list(addr))?;
+            }
+            ByteCode::LOADI => {
+                let idx = self.pop_top()?;
+                let xs = self.pop_top()?;
+                let data = match xs.data {
+                    EstaType::List(addr) => {
+                        let xs = self.heap.get_list(addr)?;
+                        xs[EstaData::eval_index(idx, xs.len())?].clone()
+                    }
+                    EstaType::Str(s) => {
+                        let idx = EstaData::eval_index(idx, s.chars().count())?;
+                        EstaData::new_str(s.chars().nth(idx).unwrap().to_string())
+                    }
+                    _ => return Err("Only strings and lists can be indexed".into()),
+                };
+                self.push_top(data)?;
+            }
+            ByteCode::STOREI => {
+                let idx = self.pop_top()?;
+                let addr = self.pop_top()?.eval_list()?;
+                let data = self.peek_top()?;
+                let idx = EstaData::eval_index(idx, self.heap.get_list(addr)?.len())?;
+                self.heap.store(addr, idx, data)?;
             }
             ByteCode::PUSHE => {
-                let local_count = self.read_inst_i16() as usize;
-                let mut frame = Vec::new();
-                frame.resize(local_count, Default::default());
+                let local_count = self.read_inst_u16() as usize;
+                let frame = vec![EstaData::default(); local_count];
+                self.heap.reserve(VirtualMachine::env_size(&frame))?;
                 self.env.push(frame);
             }
             ByteCode::POPE => {
-                self.env.pop();
+                self.truncate_env(self.env.len().saturating_sub(1));
             }
             ByteCode::PUSHS => {
+                self.heap.reserve(VirtualMachine::env_size(&[]))?;
                 self.stack.push(Vec::new());
             }
             ByteCode::POPS => {
-                self.stack.pop();
+                self.truncate_stack(self.stack.len().saturating_sub(1));
+            }
+            ByteCode::CALL => {
+                let addr = self.read_inst_u16() as usize;
+                let argc = self.read_inst_u16() as usize;
+                let args = self.pop_args(argc)?;
+                self.invoke(addr, args)?;
+            }
+            ByteCode::CALLN => {
+                let import = self.read_inst_u16() as usize;
+                let argc = self.read_inst_u16() as usize;
+                let args = self.pop_args(argc)?;
+
+                let id = *self.links.get(import).ok_or("Natives are not linked")?;
+                let data = self.natives.call(id, &mut self.heap, args)?;
+                self.push_top(data)?;
+            }
+            ByteCode::CALLM => {
+                let method = self.read_inst_u16() as usize;
+                let argc = self.read_inst_u16() as usize;
+                let args = self.pop_args(argc)?;
+                let object = match self.pop_top()?.data {
+                    EstaType::Object(object) => object,
+                    _ => return Err("Only host objects have methods".into()),
+                };
+                let data = object.call(&self.fields[method], &mut self.heap, args)?;
+                self.push_top(data)?;
+            }
+            ByteCode::RET => {
+                let value = self.pop_top()?;
+                let frame = self.frames.pop().ok_or("Return outside of a function")?;
+                self.heap.release(frame.bytes);
+                self.truncate_env(frame.env_len);
+                self.truncate_stack(frame.stack_len);
+                self.push_top(value)?;
+                self.context = frame.context;
+                self.pc = frame.ret_pc;
+            }
+            ByteCode::NEW => {
+                let tag = self.read_inst_u16();
+                let size = self.read_inst_u16() as usize;
+                let mut cells = vec![EstaData::default(); size];
+                cells[0] = EstaData::new_int(tag as i32);
+                cells[1] = EstaData::new_int(size as i32);
+                let addr = self.heap.alloc(HeapObject::Struct(cells))?;
+                self.push_top(EstaData::new_struct(addr))?;
+            }
+            ByteCode::LOADF => {
+                let field = self.read_inst_u16() as usize;
+                let addr = self.pop_top()?.eval_struct()?;
+                let offset = self.field_offset(addr, field)?;
+                let data = self.heap.get_struct(addr)?[offset].clone();
+                self.push_top(data)?;
+            }
+            ByteCode::STOREF => {
+                let field = self.read_inst_u16() as usize;
+                let addr = self.pop_top()?.eval_struct()?;
+                let offset = self.field_offset(addr, field)?;
+                let data = self.peek_top()?;
+                self.heap.store(addr, offset, data)?;
+            }
+            ByteCode::CHECK => {
+                let kind = self.read_inst_u16() as usize;
+                let tag = self.read_inst_u16() as usize;
+                let expected = EstaKind::iter().nth(kind).ok_or("Unknown type kind")?;
+                let data = self.peek_top()?;
+                let found = match data.data {
+                    EstaType::Struct(addr) => Some(self.struct_tag(addr)?),
+                    _ => None,
+                };
+                if data.data.kind() != expected || (found.is_some() && found != Some(tag)) {
+                    let message = format!(
+                        "Expected {} but found {}",
+                        self.kind_name(expected, tag),
+                        self.kind_name(data.data.kind(), found.unwrap_or_default())
+                    );
+                    return Err(EstaError::runtime(message));
+                }
             }
         }
 
         Ok(VMStatus::RUNNING)
     }
 
+    fn struct_tag(&self, addr: usize) -> Result<usize, &'static str> {
+        match self.heap.get_struct(addr)?[0].data {
+            EstaType::Num(tag) => Ok(tag as usize),
+            _ => Err("Struct is missing its tag"),
+        }
+    }
+
+    // Structs are named by their declaration, every other kind by itself
+    fn kind_name(&self, kind: EstaKind, tag: usize) -> String {
+        match (kind, self.structs.get(tag)) {
+            (EstaKind::Struct, Some(id)) => format!("Struct {}", id),
+            _ => kind.to_string(),
+        }
+    }
+
+    // Looks up where a field lives in the struct at addr
+    fn field_offset(&self, addr: usize, field: usize) -> Result<usize, &'static str> {
+        let tag = self.struct_tag(addr)?;
+        self.layouts
+            .get(&(tag, field))
+            .cloned()
+            .ok_or("Struct has no such field")
+    }
+
     fn peek_top(&mut self) -> Result<EstaData, &'static str> {
         let idx = self.stack.len() - 1;
-        self.stack[idx]
-            .last()
-            .cloned()
-            .ok_or_else(|| "Frame is empty")
+        self.stack[idx].last().cloned().ok_or("Frame is empty")
     }
 
     fn pop_top(&mut self) -> Result<EstaData, &'static str> {
         let idx = self.stack.len() - 1;
-        let top = self.peek_top();
-        self.stack[idx].pop();
-        top
+        let top = self.stack[idx].pop().ok_or("Frame is empty")?;
+        self.heap.release(top.size());
+        Ok(top)
     }
 
-    fn push_top(&mut self, data: EstaData) {
+    // Pops the top count items off the stack, in the order they were pushed
+    fn pop_args(&mut self, count: usize) -> Result<Vec<EstaData>, &'static str> {
+        let idx = self.stack.len() - 1;
+        if self.stack[idx].len() < count {
+            return Err("Not enough arguments on the stack");
+        }
+        let split = self.stack[idx].len() - count;
+        let args = self.stack[idx].split_off(split);
+        self.heap.release(VirtualMachine::cells_size(&args));
+        Ok(args)
+    }
+
+    // Pushes a call frame that returns to the current instruction and jumps to the function
+    fn invoke(&mut self, addr: usize, args: Vec<EstaData>) -> Result<(), &'static str> {
+        if self
+            .max_depth
+            .is_some_and(|depth| self.frames.len() >= depth)
+        {
+            return Err(STACK_OVERFLOW);
+        }
+        // The arguments become the new stack frame, which is counted on its own
+        let bytes = size_of::<CallFrame>();
+        self.heap.reserve(bytes + VirtualMachine::env_size(&args))?;
+        let context = self
+            .functions
+            .get(&addr)
+            .cloned()
+            .unwrap_or_else(|| "UNKNOWN".to_string());
+        self.frames.push(CallFrame {
+            ret_pc: self.pc,
+            env_len: self.env.len(),
+            stack_len: self.stack.len(),
+            context: std::mem::replace(&mut self.context, context),
+            bytes,
+        });
+        self.stack.push(args);
+        self.pc = addr;
+        Ok(())
+    }
+
+    fn cells_size(values: &[EstaData]) -> usize {
+        values.iter().map(EstaData::size).sum()
+    }
+
+    fn env_size(frame: &[EstaData]) -> usize {
+        size_of::<Vec<EstaData>>() + VirtualMachine::cells_size(frame)
+    }
+
+    fn truncate_env(&mut self, len: usize) {
+        for frame in self.env.split_off(len.min(self.env.len())) {
+            self.heap.release(VirtualMachine::env_size(&frame));
+        }
+    }
+
+    // Stack frames are counted like env frames, except for the first one, which
+    // the VM sets up before anything else
+    fn truncate_stack(&mut self, len: usize) {
+        for frame in self.stack.split_off(len.clamp(1, self.stack.len())) {
+            self.heap.release(VirtualMachine::env_size(&frame));
+        }
+    }
+
+    // Stores data into a variable, counting it in place of the value it overwrites
+    fn store(&mut self, frame: usize, idx: usize, data: EstaData) -> Result<(), &'static str> {
+        let cell = &mut self.env[frame][idx];
+        self.heap.replace(cell.size(), data.size())?;
+        *cell = data;
+        Ok(())
+    }
+
+    // The VM sets up the global frame before anything else
+    fn globals(&mut self) -> Result<&mut Vec<EstaData>, &'static str> {
+        self.env.first_mut().ok_or("There is no global frame")
+    }
+
+    fn push_top(&mut self, data: EstaData) -> Result<(), &'static str> {
+        self.heap.reserve(data.size())?;
         let idx = self.stack.len() - 1;
         self.stack[idx].push(data);
+        Ok(())
+    }
+
+    fn binary_op(
+        &mut self,
+        op: fn(EstaData, EstaData) -> Result<EstaData, &'static str>,
+    ) -> Result<(), &'static str> {
+        let rhs = self.pop_top()?;
+        let lhs = self.pop_top()?;
+        self.push_top(op(lhs, rhs)?)
+    }
+
+    fn unary_op(
+        &mut self,
+        op: fn(EstaData) -> Result<EstaData, &'static str>,
+    ) -> Result<(), &'static str> {
+        let rhs = self.pop_top()?;
+        self.push_top(op(rhs)?)
     }
 
-    fn read_inst_i16(&mut self) -> i16 {
+    fn read_inst_u16(&mut self) -> u16 {
         let upper = self.insts[self.pc];
         self.pc += 1;
         let lower = self.insts[self.pc];
         self.pc += 1;
-        i16::from_le_bytes([upper, lower])
+        u16::from_le_bytes([upper, lower])
     }
 }
 
@@ -175,6 +806,22 @@ impl fmt::Display for VirtualMachine {
     }
 }
 
+/// Stops a VM from any thread, see VirtualMachine::cancel_handle
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum VMStatus {
     HALTED,
@@ -198,10 +845,154 @@ impl EstaData {
             data: EstaType::Bool(data),
         }
     }
+    pub fn new_struct(addr: usize) -> EstaData {
+        EstaData {
+            data: EstaType::Struct(addr),
+        }
+    }
+    pub fn new_str(data: String) -> EstaData {
+        EstaData {
+            data: EstaType::Str(data),
+        }
+    }
+
+    /// Bytes counted for the value, see Heap
+    pub fn size(&self) -> usize {
+        match &self.data {
+            EstaType::Str(s) => CELL_SIZE + s.len(),
+            _ => CELL_SIZE,
+        }
+    }
+    // The address of the struct or list on the heap the value refers to
+    fn address(&self) -> Option<usize> {
+        match self.data {
+            EstaType::Struct(addr) | EstaType::List(addr) => Some(addr),
+            _ => None,
+        }
+    }
+    // Adding two strings concatenates them
     pub fn new_add(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        if let (EstaType::Str(lhs), EstaType::Str(rhs)) = (&lhs.data, &rhs.data) {
+            return Ok(EstaData::new_str(format!("{}{}", lhs, rhs)));
+        }
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers and strings can be added")?;
+        let result = lhs.checked_add(rhs).ok_or("Integer overflow")?;
+        Ok(EstaData::new_int(result))
+    }
+    pub fn new_sub(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be subtracted")?;
+        let result = lhs.checked_sub(rhs).ok_or("Integer overflow")?;
+        Ok(EstaData::new_int(result))
+    }
+    pub fn new_mul(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be multiplied")?;
+        let result = lhs.checked_mul(rhs).ok_or("Integer overflow")?;
+        Ok(EstaData::new_int(result))
+    }
+    pub fn new_div(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be divided")?;
+        if rhs == 0 {
+            return Err("Division by zero");
+        }
+        let result = lhs.checked_div(rhs).ok_or("Integer overflow")?;
+        Ok(EstaData::new_int(result))
+    }
+    pub fn new_mod(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers have a remainder")?;
+        if rhs == 0 {
+            return Err("Division by zero");
+        }
+        let result = lhs.checked_rem(rhs).ok_or("Integer overflow")?;
+        Ok(EstaData::new_int(result))
+    }
+    pub fn new_gt(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be compared")?;
+        Ok(EstaData::new_bool(lhs > rhs))
+    }
+    pub fn new_ge(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be compared")?;
+        Ok(EstaData::new_bool(lhs >= rhs))
+    }
+    pub fn new_lt(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be compared")?;
+        Ok(EstaData::new_bool(lhs < rhs))
+    }
+    pub fn new_le(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        let (lhs, rhs) = EstaData::num_operands(lhs, rhs, "Only numbers can be compared")?;
+        Ok(EstaData::new_bool(lhs <= rhs))
+    }
+    // Values of any type may be tested for equality, values of different types are never equal
+    pub fn new_eq(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        Ok(EstaData::new_bool(lhs == rhs))
+    }
+    pub fn new_ne(lhs: EstaData, rhs: EstaData) -> Result<EstaData, &'static str> {
+        Ok(EstaData::new_bool(lhs != rhs))
+    }
+    pub fn new_not(rhs: EstaData) -> Result<EstaData, &'static str> {
+        Ok(EstaData::new_bool(!rhs.eval_bool()?))
+    }
+    pub fn new_neg(rhs: EstaData) -> Result<EstaData, &'static str> {
+        match rhs.data {
+            EstaType::Num(rhs) => Ok(EstaData::new_int(
+                rhs.checked_neg().ok_or("Integer overflow")?,
+            )),
+            _ => Err("Only numbers can be negated"),
+        }
+    }
+    pub fn new_object<T: HostObject + 'static>(object: T) -> EstaData {
+        EstaData {
+            data: EstaType::Object(HostRef::new(object)),
+        }
+    }
+    pub fn new_list(addr: usize) -> EstaData {
+        EstaData {
+            data: EstaType::List(addr),
+        }
+    }
+    pub fn eval_int(self) -> Result<i32, &'static str> {
+        if let EstaType::Num(n) = self.data {
+            Ok(n)
+        } else {
+            Err("Self is not a number type")
+        }
+    }
+    pub fn eval_str(self) -> Result<String, &'static str> {
+        if let EstaType::Str(s) = self.data {
+            Ok(s)
+        } else {
+            Err("Self is not a string type")
+        }
+    }
+    pub fn eval_struct(self) -> Result<usize, &'static str> {
+        if let EstaType::Struct(addr) = self.data {
+            Ok(addr)
+        } else {
+            Err("Self is not a struct type")
+        }
+    }
+    pub fn eval_list(self) -> Result<usize, &'static str> {
+        if let EstaType::List(addr) = self.data {
+            Ok(addr)
+        } else {
+            Err("Self is not a list type")
+        }
+    }
+    // Checks that self is a valid index into a sequence of length len
+    fn eval_index(self, len: usize) -> Result<usize, &'static str> {
+        match self.data {
+            EstaType::Num(idx) if idx >= 0 && (idx as usize) < len => Ok(idx as usize),
+            EstaType::Num(_) => Err("Index out of bounds"),
+            _ => Err("Only numbers can be used as an index"),
+        }
+    }
+    fn num_operands(
+        lhs: EstaData,
+        rhs: EstaData,
+        why: &'static str,
+    ) -> Result<(i32, i32), &'static str> {
         match (lhs.data, rhs.data) {
-            (EstaType::Num(lhs), EstaType::Num(rhs)) => Ok(EstaData::new_int(lhs + rhs)),
-            _ => Err("Incompatible Types"),
+            (EstaType::Num(lhs), EstaType::Num(rhs)) => Ok((lhs, rhs)),
+            _ => Err(why),
         }
     }
     pub fn eval_bool(self) -> Result<bool, &'static str> {
@@ -211,17 +1002,134 @@ impl EstaData {
             Err("Self is not a boolean type")
         }
     }
+    pub fn eval_object(self) -> Result<HostRef, &'static str> {
+        if let EstaType::Object(object) = self.data {
+            Ok(object)
+        } else {
+            Err("Self is not an object type")
+        }
+    }
+
+    pub fn kind(&self) -> EstaKind {
+        self.data.kind()
+    }
+}
+
+/// The kind of an EstaType, without its value. Runtime type checks compile to a
+/// CHECK of the kind the value must have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, Display)]
+pub enum EstaKind {
+    Num,
+    Bool,
+    Str,
+    Struct,
+    List,
+    Nil,
+    Object,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub enum EstaType {
     Num(i32),
     Bool(bool),
+    Str(String),
+    Struct(usize), // Address of a struct instance on the heap
+    List(usize),   // Address of a list on the heap
+    Object(HostRef),
+    #[default]
     Nil,
 }
 
-impl Default for EstaType {
-    fn default() -> Self {
-        EstaType::Nil
+impl EstaType {
+    pub fn kind(&self) -> EstaKind {
+        match self {
+            EstaType::Num(_) => EstaKind::Num,
+            EstaType::Bool(_) => EstaKind::Bool,
+            EstaType::Str(_) => EstaKind::Str,
+            EstaType::Struct(_) => EstaKind::Struct,
+            EstaType::List(_) => EstaKind::List,
+            EstaType::Object(_) => EstaKind::Object,
+            EstaType::Nil => EstaKind::Nil,
+        }
+    }
+}
+
+impl fmt::Display for EstaData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.data {
+            EstaType::Num(n) => write!(f, "{}", n),
+            EstaType::Bool(true) => write!(f, "True"),
+            EstaType::Bool(false) => write!(f, "False"),
+            EstaType::Str(s) => write!(f, "{}", s),
+            EstaType::Struct(addr) => write!(f, "<struct @{}>", addr),
+            EstaType::List(addr) => write!(f, "<list @{}>", addr),
+            EstaType::Object(object) => write!(f, "<{}>", object.type_name()),
+            EstaType::Nil => write!(f, "Nil"),
+        }
+    }
+}
+
+/// Rust values convert into the Esta values of the same type, so hosts can pass them
+/// straight to Esta code. Lists and structs live on a VM's heap, so they don't.
+impl From<i32> for EstaData {
+    fn from(n: i32) -> EstaData {
+        EstaData::new_int(n)
+    }
+}
+
+impl From<bool> for EstaData {
+    fn from(b: bool) -> EstaData {
+        EstaData::new_bool(b)
+    }
+}
+
+impl From<&str> for EstaData {
+    fn from(s: &str) -> EstaData {
+        EstaData::new_str(s.to_string())
+    }
+}
+
+impl From<String> for EstaData {
+    fn from(s: String) -> EstaData {
+        EstaData::new_str(s)
+    }
+}
+
+impl From<()> for EstaData {
+    fn from(_: ()) -> EstaData {
+        Default::default()
+    }
+}
+
+impl EstaData {
+    // Converting back into Rust fails with the same message as a failed CHECK
+    fn expect(self, kind: EstaKind) -> Result<EstaData, EstaError> {
+        if self.kind() == kind {
+            Ok(self)
+        } else {
+            let message = format!("Expected {} but found {}", kind, self.kind());
+            Err(EstaError::runtime(message))
+        }
+    }
+}
+
+impl TryFrom<EstaData> for i32 {
+    type Error = EstaError;
+    fn try_from(data: EstaData) -> Result<i32, EstaError> {
+        Ok(data.expect(EstaKind::Num)?.eval_int()?)
+    }
+}
+
+impl TryFrom<EstaData> for bool {
+    type Error = EstaError;
+    fn try_from(data: EstaData) -> Result<bool, EstaError> {
+        Ok(data.expect(EstaKind::Bool)?.eval_bool()?)
+    }
+}
+
+impl TryFrom<EstaData> for String {
+    type Error = EstaError;
+    fn try_from(data: EstaData) -> Result<String, EstaError> {
+        Ok(data.expect(EstaKind::Str)?.eval_str()?)
     }
 }
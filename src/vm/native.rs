@@ -0,0 +1,200 @@
+use crate::error::EstaError;
+use crate::vm::heap::*;
+use crate::vm::{EstaData, EstaType};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+pub type NativeFn = dyn FnMut(&mut Heap, Vec<EstaData>) -> Result<EstaData, EstaError>;
+
+/// # Native Functions
+///
+/// Natives are Rust functions that Esta code calls just like any other function.
+/// The backend can't know which natives a VM will have, so it compiles every call
+/// to a name that isn't a function or a struct into an import of that name. The
+/// VM links every import to the id of its native here before it runs and then
+/// calls natives by id, which lets embedders register their own natives right up
+/// until the program runs. Calls are checked against the signatures of the
+/// natives at compile time, so any natives besides the builtins must be declared
+/// to the middleend too.
+#[derive(Default)]
+pub struct Natives {
+    natives: Vec<Native>,
+    ids: HashMap<String, usize>,
+}
+
+struct Native {
+    arity: Option<usize>, // None when the native takes any number of arguments
+    func: Box<NativeFn>,
+}
+
+impl Natives {
+    /// Creates a registry holding every builtin native
+    pub fn new() -> Natives {
+        let mut natives: Natives = Default::default();
+        natives.register("print", None, |heap, args| output(heap, args, ""));
+        natives.register("println", None, |heap, args| output(heap, args, "\n"));
+        natives.register("input", Some(0), input);
+        natives.register("len", Some(1), len);
+        natives.register("str", Some(1), to_str);
+        natives.register("num", Some(1), to_num);
+        natives.register("assert", Some(1), assert);
+        natives.register("push", Some(2), push);
+        natives.register("pop", Some(1), pop);
+        natives
+    }
+
+    /// Registers a native under name, replacing any native already registered under it
+    pub fn register<F>(&mut self, name: &str, arity: Option<usize>, func: F) -> usize
+    where
+        F: FnMut(&mut Heap, Vec<EstaData>) -> Result<EstaData, EstaError> + 'static,
+    {
+        let native = Native {
+            arity,
+            func: Box::new(func),
+        };
+        match self.ids.get(name) {
+            Some(&id) => {
+                self.natives[id] = native;
+                id
+            }
+            None => {
+                self.ids.insert(name.to_string(), self.natives.len());
+                self.natives.push(native);
+                self.natives.len() - 1
+            }
+        }
+    }
+
+    /// The arity of every registered native, by name
+    pub fn signatures(&self) -> HashMap<String, Option<usize>> {
+        self.ids
+            .iter()
+            .map(|(name, &id)| (name.clone(), self.natives[id].arity))
+            .collect()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.ids.get(name).cloned()
+    }
+
+    pub fn call(
+        &mut self,
+        id: usize,
+        heap: &mut Heap,
+        args: Vec<EstaData>,
+    ) -> Result<EstaData, EstaError> {
+        let native = self.natives.get_mut(id).ok_or("Unknown native function")?;
+        match native.arity {
+            Some(arity) if arity != args.len() => Err("Wrong number of arguments".into()),
+            _ => (native.func)(heap, args),
+        }
+    }
+}
+
+impl fmt::Debug for Natives {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.ids.keys().collect();
+        names.sort();
+        write!(f, "Natives {:?}", names)
+    }
+}
+
+/// Formats data the way print shows it. Unlike Display, this follows lists into
+/// the heap and shows their elements, printing a list that contains itself as [...]
+pub fn format(heap: &Heap, data: &EstaData) -> Result<String, &'static str> {
+    format_nested(heap, data, &mut Vec::new())
+}
+
+fn format_nested(
+    heap: &Heap,
+    data: &EstaData,
+    seen: &mut Vec<usize>,
+) -> Result<String, &'static str> {
+    match data.data {
+        EstaType::List(addr) if seen.contains(&addr) => Ok("[...]".to_string()),
+        EstaType::List(addr) => {
+            seen.push(addr);
+            let xs = heap
+                .get_list(addr)?
+                .iter()
+                .map(|x| format_nested(heap, x, seen))
+                .collect::<Result<Vec<String>, &'static str>>()?;
+            seen.pop();
+            Ok(format!("[{}]", xs.join(", ")))
+        }
+        _ => Ok(data.to_string()),
+    }
+}
+
+fn output(heap: &mut Heap, args: Vec<EstaData>, end: &str) -> Result<EstaData, EstaError> {
+    let line = args
+        .iter()
+        .map(|x| format(heap, x))
+        .collect::<Result<Vec<String>, &'static str>>()?;
+    let mut stdout = io::stdout();
+    write!(stdout, "{}{}", line.join(" "), end).map_err(|_| "Failed to write to stdout")?;
+    stdout.flush().map_err(|_| "Failed to write to stdout")?;
+    Ok(EstaData::default())
+}
+
+// Reads a line from stdin without its line ending, or Nil at the end of input
+fn input(_heap: &mut Heap, _args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    let mut line = String::new();
+    let read = io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|_| "Failed to read from stdin")?;
+    if read == 0 {
+        return Ok(EstaData::default());
+    }
+    let line = line.trim_end_matches(&['\n', '\r'][..]);
+    Ok(EstaData::new_str(line.to_string()))
+}
+
+fn len(heap: &mut Heap, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    let len = match &args[0].data {
+        EstaType::Str(s) => s.chars().count(),
+        EstaType::List(addr) => heap.get_list(*addr)?.len(),
+        _ => return Err("Only strings and lists have a length".into()),
+    };
+    Ok(EstaData::new_int(len as i32))
+}
+
+fn to_str(heap: &mut Heap, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    Ok(EstaData::new_str(format(heap, &args[0])?))
+}
+
+fn to_num(_heap: &mut Heap, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    match &args[0].data {
+        EstaType::Num(n) => Ok(EstaData::new_int(*n)),
+        EstaType::Bool(b) => Ok(EstaData::new_int(*b as i32)),
+        EstaType::Str(s) => s
+            .trim()
+            .parse()
+            .map(EstaData::new_int)
+            .map_err(|_| "String is not a number".into()),
+        _ => Err("Only numbers, booleans and strings can be converted to a number".into()),
+    }
+}
+
+fn assert(_heap: &mut Heap, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    if args[0].clone().eval_bool()? {
+        Ok(EstaData::default())
+    } else {
+        Err("Assertion failed".into())
+    }
+}
+
+fn push(heap: &mut Heap, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    let mut args = args.into_iter();
+    let addr = args.next().unwrap().eval_list()?;
+    heap.push_list(addr, args.next().unwrap())?;
+    Ok(EstaData::default())
+}
+
+fn pop(heap: &mut Heap, args: Vec<EstaData>) -> Result<EstaData, EstaError> {
+    let addr = args[0].clone().eval_list()?;
+    let data = heap.pop_list(addr)?;
+    Ok(data.ok_or("Cannot pop from an empty list")?)
+}
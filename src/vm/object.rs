@@ -0,0 +1,84 @@
+use crate::error::EstaError;
+use crate::vm::heap::Heap;
+use crate::vm::EstaData;
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/// # Host Objects
+///
+/// A host object is a Rust value that scripts hold on to without being able to
+/// look inside of it, such as a database handle. Scripts use it through its
+/// methods, which are called with the usual dot syntax: `db.query(x)`.
+///
+/// Hosts hand objects over by wrapping them with EstaData::new_object, then
+/// returning them from a native or setting them as a global. Every copy of the
+/// value refers to the same object, which is dropped as soon as the last copy is
+/// released. Lists are never freed, so an object put in a list lives as long as
+/// the VM does.
+pub trait HostObject {
+    /// The name scripts see for this kind of object
+    fn type_name(&self) -> &str;
+
+    /// Calls the named method. An object without a method of that name should
+    /// return an error saying so.
+    fn call(
+        &mut self,
+        method: &str,
+        heap: &mut Heap,
+        args: Vec<EstaData>,
+    ) -> Result<EstaData, EstaError>;
+}
+
+/// A shared reference to a host object. Two references are only equal if they
+/// refer to the very same object.
+#[derive(Clone)]
+pub struct HostRef(Rc<RefCell<dyn HostObject>>);
+
+impl HostRef {
+    pub fn new<T: HostObject + 'static>(object: T) -> HostRef {
+        HostRef(Rc::new(RefCell::new(object)))
+    }
+
+    // An object that is in the middle of a method call can't be looked at
+    pub fn type_name(&self) -> String {
+        match self.0.try_borrow() {
+            Ok(object) => object.type_name().to_string(),
+            Err(_) => "Object".to_string(),
+        }
+    }
+
+    pub fn call(
+        &self,
+        method: &str,
+        heap: &mut Heap,
+        args: Vec<EstaData>,
+    ) -> Result<EstaData, EstaError> {
+        let mut object = self
+            .0
+            .try_borrow_mut()
+            .map_err(|_| "Host object is already in use")?;
+        object.call(method, heap, args)
+    }
+}
+
+impl PartialEq for HostRef {
+    fn eq(&self, other: &HostRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for HostRef {}
+
+impl Hash for HostRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Rc::as_ptr(&self.0) as *const () as usize).hash(state);
+    }
+}
+
+impl fmt::Debug for HostRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostRef({})", self.type_name())
+    }
+}
@@ -1,19 +1,17 @@
-use crate::backend::program::*;
-use crate::vm::bytecode::*;
+use crate::error::EstaError;
 use crate::vm::*;
-use std::collections::HashMap;
 
 extern crate env_logger;
 extern crate log;
 
-fn run_instructions(insts: &Vec<MetaInst>, consts: Vec<EstaData>) -> VirtualMachine {
+fn run_instructions(insts: &[MetaInst], consts: Vec<EstaData>) -> VirtualMachine {
     let _ = env_logger::builder()
         .default_format_timestamp(false)
         .try_init();
 
     // Use the AsmCtx method to create a new program
-    let ctx = AsmCtx::new_metainst(insts.clone());
-    let mut prog: Program = ctx.assemble();
+    let ctx = AsmCtx::new_metainst(insts.to_vec());
+    let mut prog: Program = ctx.assemble().unwrap();
     prog.consts = consts;
 
     run_program(prog)
@@ -22,7 +20,7 @@ fn run_instructions(insts: &Vec<MetaInst>, consts: Vec<EstaData>) -> VirtualMach
 fn run_program(prog: Program) -> VirtualMachine {
     let mut vm = VirtualMachine::new(prog);
     let res = vm.run();
-    if let Err(e) = res {
+    if let Err(e) = &res {
         error!("Test finished with error: {}", e);
     }
     assert!(res.is_ok());
@@ -134,7 +132,784 @@ fn test_vm_const() {
     ];
 
     let ctx = AsmCtx::new_metainst(instructions);
-    let prog = ctx.assemble();
+    let prog = ctx.assemble().unwrap();
 
     run_program(prog);
 }
+
+#[test]
+fn test_vm_label() {
+    let instructions = vec![
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_bool(false)),
+        MetaInst::ByteCode(ByteCode::JUMPF),
+        MetaInst::Label("alter".to_string()),
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_int(1)),
+        MetaInst::ByteCode(ByteCode::JUMP),
+        MetaInst::Label("cont".to_string()),
+        MetaInst::Label("alter".to_string()),
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_int(2)),
+        MetaInst::Label("cont".to_string()),
+        MetaInst::ByteCode(ByteCode::HALT),
+    ];
+
+    let ctx = AsmCtx::new_metainst(instructions);
+    let prog = ctx.assemble().unwrap();
+
+    let mut vm = run_program(prog);
+    assert_eq!(vm.pop_top(), Ok(EstaData::new_int(2)));
+    assert!(vm.pop_top().is_err());
+}
+
+#[test]
+fn test_vm_call() {
+    let instructions = vec![
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_int(3)),
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_int(4)),
+        MetaInst::ByteCode(ByteCode::CALL),
+        MetaInst::Label("add".to_string()),
+        MetaInst::Number(2),
+        MetaInst::ByteCode(ByteCode::HALT),
+        MetaInst::Label("add".to_string()),
+        MetaInst::ByteCode(ByteCode::PUSHE),
+        MetaInst::Number(2),
+        MetaInst::ByteCode(ByteCode::STOREV),
+        MetaInst::Number(0),
+        MetaInst::Number(1),
+        MetaInst::ByteCode(ByteCode::POP),
+        MetaInst::ByteCode(ByteCode::STOREV),
+        MetaInst::Number(0),
+        MetaInst::Number(0),
+        MetaInst::ByteCode(ByteCode::POP),
+        // Leave an extra scope open to check that RET unwinds it
+        MetaInst::ByteCode(ByteCode::PUSHE),
+        MetaInst::Number(0),
+        MetaInst::ByteCode(ByteCode::LOADV),
+        MetaInst::Number(1),
+        MetaInst::Number(0),
+        MetaInst::ByteCode(ByteCode::LOADV),
+        MetaInst::Number(1),
+        MetaInst::Number(1),
+        MetaInst::ByteCode(ByteCode::ADD),
+        MetaInst::ByteCode(ByteCode::RET),
+    ];
+
+    let ctx = AsmCtx::new_metainst(instructions);
+    let prog = ctx.assemble().unwrap();
+
+    let mut vm = run_program(prog);
+    assert_eq!(vm.pop_top(), Ok(EstaData::new_int(7)));
+    assert!(vm.pop_top().is_err());
+    // Only the global frame is left
+    assert_eq!(vm.env.len(), 1);
+    assert_eq!(vm.stack.len(), 1);
+}
+
+#[test]
+fn test_vm_ret_outside_function() {
+    let instructions = vec![
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_int(3)),
+        MetaInst::ByteCode(ByteCode::RET),
+    ];
+
+    let ctx = AsmCtx::new_metainst(instructions);
+    let mut vm = VirtualMachine::new(ctx.assemble().unwrap());
+    assert!(vm.run().is_err());
+}
+
+fn eval_binary(op: ByteCode, lhs: EstaData, rhs: EstaData) -> Result<EstaData, EstaError> {
+    let instructions = vec![
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(lhs),
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(rhs),
+        MetaInst::ByteCode(op),
+        MetaInst::ByteCode(ByteCode::HALT),
+    ];
+
+    let ctx = AsmCtx::new_metainst(instructions);
+    let mut vm = VirtualMachine::new(ctx.assemble().unwrap());
+    vm.run()?;
+    Ok(vm.pop_top()?)
+}
+
+#[test]
+fn test_vm_arithmetic() {
+    let num = EstaData::new_int;
+    let cases = vec![
+        (ByteCode::ADD, 7, 2, num(9)),
+        (ByteCode::SUB, 7, 2, num(5)),
+        (ByteCode::MUL, 7, 2, num(14)),
+        (ByteCode::DIV, 7, 2, num(3)),
+        (ByteCode::MOD, 7, 2, num(1)),
+        (ByteCode::GT, 7, 2, EstaData::new_bool(true)),
+        (ByteCode::GE, 2, 2, EstaData::new_bool(true)),
+        (ByteCode::LT, 7, 2, EstaData::new_bool(false)),
+        (ByteCode::LE, 7, 2, EstaData::new_bool(false)),
+        (ByteCode::EQ, 7, 7, EstaData::new_bool(true)),
+        (ByteCode::NE, 7, 7, EstaData::new_bool(false)),
+    ];
+
+    for (op, lhs, rhs, expected) in cases {
+        assert_eq!(eval_binary(op, num(lhs), num(rhs)), Ok(expected));
+    }
+}
+
+fn compile(program: &str) -> Program {
+    use crate::{backend, frontend, middleend};
+
+    let stmts = frontend::run(program).unwrap();
+    let (stmts, md) = middleend::run(stmts).unwrap();
+    backend::generate(stmts, md).unwrap()
+}
+
+// Runs a program, returning the VM along with the program's result
+fn run_source(program: &str) -> Result<(VirtualMachine, EstaData), EstaError> {
+    use crate::{backend, frontend, middleend};
+
+    let stmts = frontend::run(program).map_err(|mut errors| errors.remove(0))?;
+    let (stmts, md) = middleend::run(stmts).map_err(|mut errors| errors.remove(0))?;
+    let prog = backend::generate(stmts, md)?;
+    let mut vm = VirtualMachine::new(prog);
+    let result = vm.run()?;
+    Ok((vm, result))
+}
+
+#[test]
+fn test_vm_logical() {
+    let cases = vec![
+        ("True and True", true),
+        ("True and False", false),
+        ("False and True", false),
+        ("True or False", true),
+        ("False or True", true),
+        ("False or False", false),
+        ("False or True and False", false),
+    ];
+
+    for (expr, expected) in cases {
+        let program = format!("fun main() {{ return {}; }}", expr);
+        let (_, result) = run_source(&program).unwrap();
+        assert_eq!(result, EstaData::new_bool(expected), "{}", expr);
+    }
+}
+
+#[test]
+fn test_vm_short_circuit() {
+    // boom() fails at runtime, so a program only succeeds if it is never called
+    let boom = "fun boom() { return 1 / 0; }";
+
+    let program = format!("{} fun main() {{ return False and boom(); }}", boom);
+    let (_, result) = run_source(&program).unwrap();
+    assert_eq!(result, EstaData::new_bool(false));
+
+    let program = format!("{} fun main() {{ return True or boom(); }}", boom);
+    let (_, result) = run_source(&program).unwrap();
+    assert_eq!(result, EstaData::new_bool(true));
+
+    let program = format!("{} fun main() {{ return True and boom(); }}", boom);
+    assert!(run_source(&program).is_err());
+
+    let program = format!("{} fun main() {{ return False or boom(); }}", boom);
+    assert!(run_source(&program).is_err());
+}
+
+#[test]
+fn test_vm_unary() {
+    let instructions = vec![
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_int(4)),
+        MetaInst::ByteCode(ByteCode::NEG),
+        MetaInst::ByteCode(ByteCode::LOADC),
+        MetaInst::Const(EstaData::new_bool(true)),
+        MetaInst::ByteCode(ByteCode::NOT),
+        MetaInst::ByteCode(ByteCode::HALT),
+    ];
+
+    let ctx = AsmCtx::new_metainst(instructions);
+    let mut vm = run_program(ctx.assemble().unwrap());
+    assert_eq!(vm.pop_top(), Ok(EstaData::new_bool(false)));
+    assert_eq!(vm.pop_top(), Ok(EstaData::new_int(-4)));
+}
+
+#[test]
+fn test_vm_type_errors() {
+    let num = EstaData::new_int;
+    let b = EstaData::new_bool;
+    assert!(eval_binary(ByteCode::ADD, num(1), b(true)).is_err());
+    assert!(eval_binary(ByteCode::LT, b(false), num(1)).is_err());
+    assert!(eval_binary(ByteCode::DIV, num(1), num(0)).is_err());
+    assert!(eval_binary(ByteCode::MOD, num(1), num(0)).is_err());
+    assert!(eval_binary(ByteCode::MUL, num(i32::MAX), num(2)).is_err());
+    assert_eq!(eval_binary(ByteCode::EQ, num(1), b(true)), Ok(b(false)));
+}
+
+#[test]
+fn test_vm_struct() {
+    let program = "
+        struct Vector { x, y }
+        struct Color { red, green, blue }
+
+        fun main() {
+            var c = Color();
+            var v = Vector();
+            var w = v;
+            v.x = 43;
+            w.y = 55;
+            c.blue = v.x + v.y;
+            return c.blue;
+        }";
+    let (vm, result) = run_source(program).unwrap();
+    assert_eq!(result, EstaData::new_int(98));
+    assert_eq!(vm.heap.len(), 2);
+}
+
+#[test]
+fn test_vm_struct_missing_field() {
+    let program = "
+        struct Vector { x, y }
+
+        fun main() {
+            var v = Vector();
+            return v.z;
+        }";
+    assert!(run_source(program).is_err());
+
+    let program = "
+        fun main() {
+            var v = 4;
+            return v.x;
+        }";
+    assert!(run_source(program).is_err());
+}
+
+#[test]
+fn test_vm_string() {
+    let cases = vec![
+        (r#""foo" + "bar""#, EstaData::new_str("foobar".to_string())),
+        (r#""foo" == "foo""#, EstaData::new_bool(true)),
+        (r#""foo" != "bar""#, EstaData::new_bool(true)),
+        (r#""foo" == 3"#, EstaData::new_bool(false)),
+        (r#"len("héllo\n")"#, EstaData::new_int(6)),
+        (r#"len("")"#, EstaData::new_int(0)),
+    ];
+
+    for (expr, expected) in cases {
+        let program = format!("fun main() {{ return {}; }}", expr);
+        let (_, result) = run_source(&program).unwrap();
+        assert_eq!(result, expected, "{}", expr);
+    }
+
+    assert!(run_source(r#"fun main() { return "foo" + 1; }"#).is_err());
+    assert!(run_source(r#"fun main() { return "foo" < "bar"; }"#).is_err());
+    assert!(run_source("fun main() { return len(4); }").is_err());
+}
+
+#[test]
+fn test_vm_list() {
+    let program = "
+        fun main() {
+            var xs = [1, 2];
+            var ys = xs;
+            push(ys, 3);
+            xs[0] = 5;
+            return [len(xs), xs[0], ys[0], pop(xs), len(ys), \"abc\"[1]];
+        }";
+    let (vm, list) = run_source(program).unwrap();
+    let addr = list.eval_list().unwrap();
+    let expected = vec![
+        EstaData::new_int(3),
+        EstaData::new_int(5),
+        EstaData::new_int(5),
+        EstaData::new_int(3),
+        EstaData::new_int(2),
+        EstaData::new_str("b".to_string()),
+    ];
+    assert_eq!(vm.heap.get_list(addr), Ok(&expected));
+}
+
+#[test]
+fn test_vm_list_errors() {
+    assert!(run_source("fun main() { return [1][1]; }").is_err());
+    assert!(run_source("fun main() { return [1][0 - 1]; }").is_err());
+    assert!(run_source("fun main() { return [1][True]; }").is_err());
+    assert!(run_source("fun main() { return pop([]); }").is_err());
+    assert!(run_source("fun main() { var x = 1; x[0] = 2; }").is_err());
+    assert!(run_source("fun main() { return push(3, 4); }").is_err());
+}
+
+#[test]
+fn test_vm_natives() {
+    let cases = vec![
+        (
+            "str(12) + str([1, [True]])",
+            EstaData::new_str("12[1, [True]]".to_string()),
+        ),
+        ("num(\" 42 \") + num(True)", EstaData::new_int(43)),
+        ("len(\"héllo\")", EstaData::new_int(5)),
+        ("println(\"natives\", 1)", EstaData::default()),
+        ("assert(1 < 2)", EstaData::default()),
+    ];
+
+    for (expr, expected) in cases {
+        let program = format!("fun main() {{ return {}; }}", expr);
+        let (_, result) = run_source(&program).unwrap();
+        assert_eq!(result, expected, "{}", expr);
+    }
+
+    assert_eq!(
+        run_source("fun main() { assert(1 > 2); }")
+            .unwrap_err()
+            .message,
+        "Assertion failed"
+    );
+    assert!(run_source("fun main() { return num(\"abc\"); }").is_err());
+    assert!(run_source("fun main() { return len(1, 2); }").is_err());
+    assert_eq!(
+        run_source("fun main() { return nope(); }")
+            .unwrap_err()
+            .message,
+        "nope is not a function"
+    );
+}
+
+#[test]
+fn test_vm_register_native() {
+    use crate::{backend, frontend, middleend};
+
+    let program = "
+        fun len(x) { return 7; }
+        fun main() { return double(len([])) + triple(1); }";
+    let mut settings: middleend::Settings = Default::default();
+    settings.natives.insert("double".to_string(), Some(1));
+    settings.natives.insert("triple".to_string(), Some(1));
+    let stmts = frontend::run(program).unwrap();
+    let (stmts, md) = middleend::run_with(stmts, &settings).unwrap();
+    let prog = backend::generate(stmts, md).unwrap();
+    assert_eq!(prog.natives, vec!["double", "triple"]);
+
+    // Natives declared to the middleend but never registered fail before anything runs
+    let mut vm = VirtualMachine::new(prog.clone());
+    let error = vm.run().unwrap_err();
+    assert_eq!(error.message, "Native function double is not registered");
+    assert_eq!(vm.pc, 0);
+
+    let mut vm = VirtualMachine::new(prog);
+    vm.register_native("double", Some(1), |_heap, args| {
+        let n = args[0].clone().eval_int()?;
+        Ok(EstaData::new_int(n * 2))
+    });
+    vm.register_native("triple", Some(1), |_heap, _args| Err("Not this one".into()));
+    vm.register_native("triple", Some(1), |_heap, args| {
+        let n = args[0].clone().eval_int()?;
+        Ok(EstaData::new_int(n * 3))
+    });
+    assert_eq!(vm.run(), Ok(EstaData::new_int(17)));
+}
+
+#[test]
+fn test_vm_globals() {
+    let program = "
+        var count = 0;
+        var step = 2;
+        fun tick(n) {
+            var step = n;
+            count = count + step;
+            return count;
+        }
+        fun main() {
+            tick(3);
+            if True { var count = 10; tick(count); }
+            return count + step;
+        }";
+    let (_, result) = run_source(program).unwrap();
+    assert_eq!(result, EstaData::new_int(15));
+
+    // Until a block declares a variable, the one further out is still in view
+    let program = "fun main() { var x = 1; if True { var y = x; var x = 2; return y + x; } }";
+    let (_, result) = run_source(program).unwrap();
+    assert_eq!(result, EstaData::new_int(3));
+
+    let error = run_source("fun main() { return missing; }").unwrap_err();
+    assert_eq!(error.kind, crate::error::ErrorKind::Compile);
+}
+
+#[test]
+fn test_vm_run_again() {
+    // Running again only runs main, which leaves nothing behind on the stack
+    let (mut vm, result) = run_source("var n = 0; fun main() { n = n + 1; return n; }").unwrap();
+    assert_eq!(result, EstaData::new_int(1));
+    assert_eq!(vm.run(), Ok(EstaData::new_int(2)));
+    assert!(vm.stack[0].is_empty());
+
+    let error = vm.enter("main", vec![EstaData::new_int(1)]).unwrap_err();
+    assert_eq!(error.message, "main takes 0 arguments but 1 was given");
+    assert!(vm.frames.is_empty());
+}
+
+#[test]
+fn test_vm_type_guards() {
+    let program = "
+        struct Point { x: num }
+        struct Vector { x, y }
+        fun id(x) { return x; }
+        fun double(n: num) -> num { return n * 2; }
+        fun flag(b: bool) -> bool { return b; }
+        fun name(p: Point) -> str { return id(p.x); }
+        fun main() { DEFINITION }";
+    let run = |body: &str| run_source(&program.replace("DEFINITION", body));
+
+    let (_, result) = run("return double(id(4)) + double(1);").unwrap();
+    assert_eq!(result, EstaData::new_int(10));
+
+    let cases = vec![
+        ("double(id(\"4\"));", "Expected Num but found Str"),
+        ("var a: num = id(Nil);", "Expected Num but found Nil"),
+        (
+            "var p = Point(); p.x = id([]);",
+            "Expected Num but found List",
+        ),
+        (
+            "name(id(Vector()));",
+            "Expected Struct Point but found Struct Vector",
+        ),
+        (
+            "var p = Point(); p.x = 1; name(p);",
+            "Expected Str but found Num",
+        ),
+        // Logical operators give back their rhs, whatever it is
+        ("flag(True and id(5));", "Expected Bool but found Num"),
+        ("flag(False or id(\"x\"));", "Expected Bool but found Str"),
+    ];
+    for (body, message) in cases {
+        let error = run(body).unwrap_err();
+        assert_eq!(error.kind, crate::error::ErrorKind::Runtime);
+        assert_eq!(error.message, message, "{}", body);
+    }
+
+    // Only annotations are guarded, not the types unannotated variables were inferred to have
+    let (_, result) = run("var x = 1; x = str(x); var p = Point(); p = 2; return x;").unwrap();
+    assert_eq!(result, EstaData::new_str("1".to_string()));
+
+    // Without a value, a function returns Nil, which is guarded all the same
+    use crate::{backend, frontend, middleend};
+    let settings = middleend::Settings {
+        strictness: middleend::typecheck::Strictness::Warn,
+        ..Default::default()
+    };
+    let cases = vec![
+        "fun f() -> num { return; } fun main() { return f(); }",
+        "fun f(x) -> num { if x { return 1; } } fun main() { f(True); return f(False); }",
+    ];
+    for program in cases {
+        let stmts = frontend::run(program).unwrap();
+        let (stmts, md) = middleend::run_with(stmts, &settings).unwrap();
+        let mut vm = VirtualMachine::new(backend::generate(stmts, md).unwrap());
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.message, "Expected Num but found Nil", "{}", program);
+    }
+
+    // Values already known to have the right type aren't checked again
+    let stmts = frontend::run("fun f(n: num) {} f(1); f(1 + 2); f(f(3));").unwrap();
+    let (stmts, md) = middleend::run_with(stmts, &settings).unwrap();
+    let prog = backend::generate(stmts, md).unwrap();
+    let checks = disassemble_u8(&prog.insts)
+        .into_iter()
+        .filter(|i| matches!(i, MetaInst::ByteCode(ByteCode::CHECK)))
+        .count();
+    assert_eq!(checks, 1);
+}
+
+#[test]
+fn test_vm_optimized() {
+    let cases = vec![
+        ("fun main() { return 2 * 3 + 1; }", 7),
+        ("fun main() { var a = 1; if 1 < 2 { a = 5; } return a; }", 5),
+        (
+            "fun main() { if False { return 1; } else { return 2; } }",
+            2,
+        ),
+        ("fun main() { var a = 4; return a; var b = 2; a = b; }", 4),
+        (
+            "fun main() { if True { return f(); } fun f() { return 3; } }",
+            3,
+        ),
+        (
+            "fun main() { var a = 9; while False { a = 1; } return a; }",
+            9,
+        ),
+    ];
+    for (program, expected) in cases {
+        let (_, result) = run_source(program).unwrap();
+        assert_eq!(result, EstaData::new_int(expected), "{}", program);
+    }
+}
+
+#[test]
+fn test_vm_peephole() {
+    let program = "
+        var total;
+        fun count(n) {
+            var i = 0;
+            var sum = 0;
+            while i < n {
+                if i % 2 == 0 {
+                    sum = sum + i;
+                } else {
+                    if i > 5 { sum = sum + 1; }
+                }
+                i = i + 1;
+            }
+            return sum;
+        }
+        total = count(10);
+        fun main() { return total; }";
+    let prog = compile(program);
+    assert!(prog.peephole.after < prog.peephole.before);
+
+    let mut vm = VirtualMachine::new(prog);
+    assert_eq!(vm.run(), Ok(EstaData::new_int(22)));
+}
+
+#[test]
+fn test_vm_consts() {
+    let prog = compile("var a; a = 1; a = \"s\"; a = 1; a = \"s\"; a = 2; a = 1;");
+    assert_eq!(
+        prog.consts,
+        vec![
+            EstaData::new_int(1),
+            EstaData::new_str("s".to_string()),
+            EstaData::new_int(2)
+        ]
+    );
+
+    // Past u16::MAX constants, loads take a wide index
+    let count = u16::MAX as i32 + 100;
+    let program: String = (0..count).map(|n| format!("a = {};\n", n)).collect();
+    let prog = compile(&format!("var a; {}", program));
+    assert_eq!(prog.consts.len(), count as usize);
+    let mut vm = VirtualMachine::new(prog);
+    vm.run().unwrap();
+    assert_eq!(vm.env[0][0], EstaData::new_int(count - 1));
+}
+
+#[test]
+fn test_vm_far_calls() {
+    let far = |count: i32| {
+        let program: String = (0..count).map(|n| format!("a = {};\n", n)).collect();
+        format!(
+            "var a; {} fun f(n) {{ if n > 0 {{ return f(n - 1) + 1; }} return a; }} fun main() {{ return f(3); }}",
+            program
+        )
+    };
+
+    // Functions placed past i16::MAX bytes are still called and jumped around in
+    let prog = compile(&far(8000));
+    assert!(prog.functions["f"].0 > i16::MAX as usize);
+    let mut vm = VirtualMachine::new(prog);
+    assert_eq!(vm.run(), Ok(EstaData::new_int(8002)));
+
+    // Past u16::MAX bytes they can't be reached at all
+    let error = run_source(&far(12_000)).unwrap_err();
+    assert_eq!(error.message, "Program is too large");
+
+    // Neither can a list be built out of more elements than an argument can count
+    let xs = "0,\n".repeat(u16::MAX as usize + 2);
+    let error = run_source(&format!("var xs = [{}];", xs)).unwrap_err();
+    assert_eq!(error.message, "Program is too large");
+}
+
+#[test]
+fn test_vm_fuel() {
+    use crate::error::ErrorKind;
+
+    let program = "
+        var spins = 0;
+        fun spin() { while True { spins = spins + 1; } }
+        fun add(a, b) { return a + b; }
+        fun main() { spin(); }";
+    let prog = compile(program);
+    let mut vm = VirtualMachine::new(prog);
+
+    vm.set_fuel(Some(1000));
+    let error = vm.call("spin", Vec::new()).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::OutOfFuel);
+    assert_eq!(vm.fuel(), Some(0));
+    assert!(vm.global("spins").unwrap().eval_int().unwrap() > 0);
+
+    // The VM is unwound and runs again once it has been refueled
+    assert_eq!(
+        vm.call("add", vec![1.into(), 2.into()]).unwrap_err().kind,
+        ErrorKind::OutOfFuel
+    );
+    vm.set_fuel(Some(1000));
+    assert_eq!(vm.call("add", vec![1.into(), 2.into()]), Ok(3.into()));
+    assert!(vm.fuel().unwrap() < 1000);
+    vm.set_fuel(None);
+    assert_eq!(vm.call("add", vec![3.into(), 4.into()]), Ok(7.into()));
+
+    // Main is unwound as well, rather than finished by the next call
+    vm.set_fuel(Some(1000));
+    assert_eq!(vm.run().unwrap_err().kind, ErrorKind::OutOfFuel);
+    assert!(vm.frames.is_empty());
+    vm.set_fuel(Some(1000));
+    assert_eq!(vm.call("add", vec![5.into(), 6.into()]), Ok(11.into()));
+}
+
+#[test]
+fn test_vm_cancel() {
+    use crate::error::ErrorKind;
+    use std::thread;
+    use std::time::Duration;
+
+    let prog = compile("fun main() { var i = 0; while True { i = i + 1; } }");
+    let mut vm = VirtualMachine::new(prog);
+
+    let handle = vm.cancel_handle();
+    assert!(!handle.is_cancelled());
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.cancel();
+    });
+    let error = vm.run().unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Cancelled);
+    assert_eq!(error.message, "The program was cancelled");
+    canceller.join().unwrap();
+    assert!(vm.cancel_handle().is_cancelled());
+}
+
+#[test]
+fn test_vm_limits() {
+    use crate::error::ErrorKind;
+
+    let program = "
+        var xs = [];
+        var text;
+        fun down(n) { if n == 0 { return 0; } return down(n - 1) + 1; }
+        fun forever(n) { return forever(n + 1); }
+        fun grow(n) { var i = 0; while i < n { push(xs, i); i = i + 1; } return len(xs); }
+        fun nest(n) { var i = 0; var ys = []; while i < n { ys = [ys, i]; i = i + 1; } return i; }
+        fun double(s) { while True { s = s + s; } }";
+    let prog = compile(program);
+    let mut vm = VirtualMachine::new(prog);
+
+    assert_eq!(vm.call("down", vec![100.into()]), Ok(100.into()));
+    let used = vm.memory_used();
+    assert_eq!(vm.call("down", vec![1000.into()]), Ok(1000.into()));
+    assert_eq!(vm.memory_used(), used);
+
+    let error = vm.call("forever", vec![0.into()]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::StackOverflow);
+    assert_eq!(error.message, "Stack overflow");
+    assert_eq!(vm.memory_used(), used);
+
+    vm.set_limits(Limits {
+        call_depth: Some(50),
+        ..Default::default()
+    });
+    assert_eq!(vm.call("down", vec![40.into()]), Ok(40.into()));
+    let error = vm.call("down", vec![60.into()]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::StackOverflow);
+
+    // Lists grown by natives count towards the limit just like new ones
+    vm.set_limits(Limits {
+        memory: Some(used + 100 * CELL_SIZE),
+        ..Default::default()
+    });
+    assert_eq!(vm.call("grow", vec![10.into()]), Ok(10.into()));
+    let error = vm.call("grow", vec![1000.into()]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::MemoryLimit);
+    assert_eq!(error.message, "Memory limit exceeded");
+    let error = vm.call("nest", vec![1000.into()]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::MemoryLimit);
+
+    // Strings count their text, wherever they are held
+    vm.collect_garbage();
+    let before = vm.memory_used();
+    vm.set_limits(Limits {
+        memory: Some(before + 10_000),
+        ..Default::default()
+    });
+    let error = vm.call("double", vec!["x".into()]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::MemoryLimit);
+    assert_eq!(vm.memory_used(), before);
+    let text = |len| EstaData::new_str("x".repeat(len));
+    vm.set_global("text", text(5000)).unwrap();
+    assert_eq!(vm.memory_used(), before + 5000);
+    let error = vm.set_global("text", text(20_000)).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::MemoryLimit);
+    vm.set_global("text", text(0)).unwrap();
+    assert_eq!(vm.memory_used(), before);
+
+    vm.set_limits(Default::default());
+    assert_eq!(vm.call("nest", vec![1000.into()]), Ok(1000.into()));
+}
+
+#[test]
+fn test_vm_collection() {
+    let program = "
+        var kept = [];
+        fun churn(n) { var i = 0; while i < n { var xs = [1, 2, 3]; i = i + 1; } return i; }
+        fun keep() { kept = [[1], [2]]; return [4, [5]]; }
+        fun first(xs) { return xs[0]; }";
+    let prog = compile(program);
+    let mut vm = VirtualMachine::new(prog);
+
+    // Objects nothing refers to any more don't count towards the limit
+    vm.set_limits(Limits {
+        memory: Some(1_000_000),
+        ..Default::default()
+    });
+    assert_eq!(vm.call("churn", vec![100_000.into()]), Ok(100_000.into()));
+    assert!(vm.memory_used() < 1_000_000);
+
+    // Objects in the globals, and those handed out to the host, stay put
+    let held = vm.call("keep", Vec::new()).unwrap();
+    vm.collect_garbage();
+    assert_eq!(vm.heap.len(), 5);
+    let used = vm.memory_used();
+    assert_eq!(vm.call("churn", vec![10.into()]), Ok(10.into()));
+    vm.collect_garbage();
+    assert_eq!(vm.memory_used(), used);
+    assert_eq!(vm.call("first", vec![held]), Ok(4.into()));
+    let kept = vm.global("kept").unwrap();
+    vm.call("keep", Vec::new()).unwrap();
+    vm.collect_garbage();
+    let inner = vm.call("first", vec![kept]).unwrap().eval_list().unwrap();
+    assert_eq!(vm.heap.get_list(inner), Ok(&vec![EstaData::new_int(1)]));
+}
+
+#[test]
+fn test_vm_spans() {
+    let program = "fun id(x) { return x; }
+fun double(n: num) -> num {
+    return n * 2;
+}
+fun main() {
+    var total = 0;
+    total = total + double(id(\"two\"));
+    return total;
+}";
+    let error = run_source(program).unwrap_err();
+    assert_eq!(error.message, "Expected Num but found Str");
+    let span = error.span.unwrap();
+    assert_eq!((span.start.line, span.start.column), (7, 28));
+    assert!(error.render(program).ends_with("^^^^^^^^^"));
+
+    let program = "fun main() {
+    var xs = [1, 2];
+    var i = 0;
+    while True {
+        i = i + 1;
+        xs[i] = xs[i - 1];
+    }
+}";
+    let error = run_source(program).unwrap_err();
+    let span = error.span.unwrap();
+    assert_eq!((span.start.line, span.start.column), (6, 9));
+}